@@ -0,0 +1,168 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Locally-synthesized responses: answering a query without ever contacting an upstream, for adblock-style
+//! blocklists and the `disable_ipv6` negative answer alike.
+
+use std::net::IpAddr;
+use trust_dns_client::{
+    op::{Message, MessageType, ResponseCode},
+    rr::{rdata::soa::SOA, record_data::RData, record_type::RecordType, resource::Record, Name},
+};
+
+/// Parameters for a synthesized `SOA` record, placed in the authority section of a NOERROR/NODATA answer.
+pub struct SoaParams {
+    /// Primary nameserver of the zone the SOA claims to describe.
+    pub mname: Name,
+    /// Responsible-party mailbox of the zone.
+    pub rname: Name,
+    /// TTL of the synthesized record itself.
+    pub ttl: u32,
+    /// Zone serial number.
+    pub serial: u32,
+    /// SOA `REFRESH`, `RETRY`, `EXPIRE`, and `MINIMUM` fields.
+    pub refresh: i32,
+    pub retry: i32,
+    pub expire: i32,
+    pub minimum: u32,
+}
+
+impl Default for SoaParams {
+    // Mirrors the placeholder smartdns uses for its synthesized negative answers, see
+    // https://github.com/pymumu/smartdns/blob/42b3e98b2a3ca90ea548f8cb5ed19a3da6011b74/src/dns_server.c#L651
+    fn default() -> Self {
+        Self {
+            mname: Name::from_utf8("a.gtld-servers.net").unwrap(),
+            rname: Name::from_utf8("nstld.verisign-grs.com").unwrap(),
+            ttl: 86400,
+            serial: 1800,
+            refresh: 1800,
+            retry: 900,
+            expire: 604800,
+            minimum: 86400,
+        }
+    }
+}
+
+/// What a `Rule` synthesizes locally instead of routing the query to an upstream.
+pub enum Synthesis {
+    /// Respond `NXDOMAIN`.
+    NxDomain,
+    /// Respond `NOERROR` with an empty answer section and a synthesized `SOA` in the authority section.
+    Soa(SoaParams),
+    /// Respond with a fixed address, e.g. `0.0.0.0` to sinkhole an ad domain.
+    Address(IpAddr),
+}
+
+/// Synthesize the response to `msg`'s (single) query according to `synthesis`.
+pub fn synthesize(mut msg: Message, synthesis: &Synthesis) -> Message {
+    let query = msg.queries().iter().next().cloned();
+    msg.set_message_type(MessageType::Response);
+
+    match synthesis {
+        Synthesis::NxDomain => {
+            msg.set_response_code(ResponseCode::NXDomain);
+        }
+        Synthesis::Soa(params) => {
+            if let Some(name) = query.map(|q| q.name().clone()) {
+                let rdata = RData::SOA(SOA::new(
+                    params.mname.clone(),
+                    params.rname.clone(),
+                    params.serial,
+                    params.refresh,
+                    params.retry,
+                    params.expire,
+                    params.minimum,
+                ));
+                // The authority section is where a SOA accompanying a negative answer belongs, not additional.
+                msg.add_name_server(Record::from_rdata(name, params.ttl, rdata));
+            }
+        }
+        Synthesis::Address(ip) => {
+            // Only answer if the configured address's family actually matches what was asked; anything else
+            // (a mismatched family, or a non-address query type like MX/TXT) gets an empty NOERROR answer
+            // rather than a record of the wrong type, which well-behaved resolvers would otherwise reject.
+            if let Some(q) = query {
+                let rdata = match (ip, q.query_type()) {
+                    (IpAddr::V4(v4), RecordType::A) => Some(RData::A(*v4)),
+                    (IpAddr::V6(v6), RecordType::AAAA) => Some(RData::AAAA(*v6)),
+                    _ => None,
+                };
+                if let Some(rdata) = rdata {
+                    msg.add_answer(Record::from_rdata(q.name().clone(), 60, rdata));
+                }
+            }
+        }
+    }
+
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns_client::op::Query;
+
+    fn query(name: &str, rtype: RecordType) -> Message {
+        let mut msg = Message::new();
+        msg.add_query(Query::query(Name::from_utf8(name).unwrap(), rtype));
+        msg
+    }
+
+    #[test]
+    fn test_nxdomain() {
+        let resp = synthesize(query("blocked.example.com", RecordType::A), &Synthesis::NxDomain);
+        assert_eq!(resp.response_code(), ResponseCode::NXDomain);
+        assert!(resp.answers().is_empty());
+    }
+
+    #[test]
+    fn test_soa_goes_in_authority_section() {
+        let resp = synthesize(
+            query("blocked.example.com", RecordType::AAAA),
+            &Synthesis::Soa(SoaParams::default()),
+        );
+        assert!(resp.answers().is_empty());
+        assert_eq!(resp.name_servers().len(), 1);
+    }
+
+    #[test]
+    fn test_address_matches_query_type() {
+        let resp = synthesize(
+            query("ads.example.com", RecordType::A),
+            &Synthesis::Address("0.0.0.0".parse().unwrap()),
+        );
+        assert_eq!(resp.answers().len(), 1);
+        assert!(matches!(resp.answers()[0].rdata(), RData::A(_)));
+    }
+
+    #[test]
+    fn test_address_skips_on_family_mismatch() {
+        let resp = synthesize(
+            query("ads.example.com", RecordType::AAAA),
+            &Synthesis::Address("0.0.0.0".parse().unwrap()),
+        );
+        assert!(resp.answers().is_empty());
+    }
+
+    #[test]
+    fn test_address_skips_on_non_address_query() {
+        let resp = synthesize(
+            query("ads.example.com", RecordType::TXT),
+            &Synthesis::Address("0.0.0.0".parse().unwrap()),
+        );
+        assert!(resp.answers().is_empty());
+    }
+}
@@ -0,0 +1,1217 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! DNSSEC chain-of-trust validation, used by `Router` when `dnssec` is enabled.
+//!
+//! This chases the delegation from the root down to the zone owning each RRset under validation, fetching
+//! `DNSKEY`/`DS` records as needed through the same upstream tag the query itself used, and cryptographically
+//! verifies every `RRSIG` along the way (RSASHA256 and ECDSAP256SHA256 only; anything else is treated as
+//! unsupported and fails closed), including that the current time falls within its inception/expiration
+//! window. A positive answer is validated RRset-by-RRset, each against the chain for
+//! its own owner name, so a CNAME aliasing into a different signed zone validates correctly. A negative
+//! answer's NSEC/NSEC3 (and any accompanying SOA) is likewise chain-validated before its denial is trusted.
+//! A zone a signed ancestor proves (via NSEC/NSEC3) carries no DS record is legitimately unsigned —
+//! overwhelmingly the common case, since most of the internet isn't DNSSEC-signed — and is accepted as
+//! `Validity::ProvablyInsecure` rather than failing closed. Wildcard expansion is not handled; an unexpected
+//! shape anywhere in the chain is `Validity::Bogus` rather than silently accepted.
+
+use crate::{error::Result, upstream::Upstreams};
+use std::collections::{HashMap, HashSet};
+use data_encoding::{BASE32HEX_NOPAD, HEXUPPER};
+use ring::{
+    digest::{digest, SHA1_FOR_LEGACY_USE_ONLY as SHA1, SHA256},
+    signature,
+};
+use std::fmt::{Debug, Display};
+use trust_dns_client::{
+    op::{Message, Query},
+    rr::{
+        dnssec::{
+            rdata::{DNSSECRData, DNSKEY, DS, NSEC, NSEC3, RRSIG},
+            Algorithm,
+        },
+        record_type::RecordType,
+        Name, RData, Record,
+    },
+};
+use trust_dns_proto::serialize::binary::{BinEncodable, BinEncoder};
+
+/// The root KSK-2017 trust anchor, as published at https://data.iana.org/root-anchors/root-anchors.xml
+/// (key tag 20326, algorithm 8, digest type 2 / SHA-256).
+const ROOT_KSK_2017_DIGEST: &str =
+    "E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D";
+
+/// The trust anchor(s) a DNSSEC chain of trust must bottom out at.
+pub struct TrustAnchor {
+    /// DS records for the root zone, as published by IANA.
+    pub root_ds: Vec<DS>,
+}
+
+impl TrustAnchor {
+    /// A `TrustAnchor` seeded with the current IANA root KSK digest.
+    pub fn root() -> Self {
+        Self {
+            root_ds: vec![DS::new(
+                20326,
+                Algorithm::RSASHA256,
+                trust_dns_client::rr::dnssec::DigestType::SHA256,
+                HEXUPPER.decode(ROOT_KSK_2017_DIGEST.as_bytes()).unwrap(),
+            )],
+        }
+    }
+}
+
+/// The outcome of validating a signed `RRset` against its covering `RRSIG`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Validity {
+    /// The chain of trust resolves cleanly; the AD bit may be set.
+    Secure,
+    /// A negative answer is provably non-existent per NSEC/NSEC3.
+    InsecureDenial,
+    /// A signed ancestor zone proved, via NSEC/NSEC3, that the answer's own zone carries no DS record — the
+    /// answer is legitimately unsigned, not merely missing a signature, so it's accepted without the AD bit.
+    ProvablyInsecure,
+    /// Signature, delegation, or denial-of-existence proof failed to validate.
+    Bogus,
+}
+
+/// The outcome of walking the chain of trust down to a particular zone (see [`validate_chain`]).
+#[derive(Debug, Clone)]
+pub enum ChainStatus {
+    /// `zone`'s `DNSKEY`s were fetched and verified all the way from the trust anchor.
+    Secure(Vec<DNSKEY>),
+    /// A signed ancestor zone proved, via NSEC/NSEC3, that no DS record exists for this delegation — the
+    /// zone (and everything under it) is legitimately unsigned.
+    Insecure,
+    /// The chain could not be verified one way or the other: a missing/invalid signature, or an unproven
+    /// claim of "no DS here".
+    Bogus,
+}
+
+// --- NSEC3 owner-name hashing (RFC 5155 section 5) ---------------------------------------------------
+
+fn nsec3_hash_once(input: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(input.len() + salt.len());
+    buf.extend_from_slice(input);
+    buf.extend_from_slice(salt);
+    digest(&SHA1, &buf).as_ref().to_vec()
+}
+
+/// Hash `name` the way an NSEC3 owner name is derived: the canonical (lowercased) wire-format name, salted
+/// and iterated `iterations` additional times, then base32hex-encoded without padding (as NSEC3 owner labels
+/// appear in a zone).
+pub fn nsec3_hash(name: &Name, iterations: u16, salt: &[u8]) -> String {
+    let mut wire = Vec::new();
+    {
+        let mut encoder = BinEncoder::new(&mut wire);
+        encoder.set_canonical_names(true);
+        name.to_lowercase()
+            .emit(&mut encoder)
+            .expect("a Name always encodes into a Vec");
+    }
+    let mut h = nsec3_hash_once(&wire, salt);
+    for _ in 0..iterations {
+        h = nsec3_hash_once(&h, salt);
+    }
+    BASE32HEX_NOPAD.encode(&h).to_ascii_lowercase()
+}
+
+/// Whether `candidate`'s NSEC3 hash falls strictly between `owner` and `next`, treating the hash space as a
+/// circular interval so the record covering the "last" name in the zone (whose `next` wraps back to the
+/// lexicographically-first owner) is handled the same way as any other.
+pub fn covers(owner: &str, next: &str, candidate: &str) -> bool {
+    if owner < next {
+        owner < candidate && candidate < next
+    } else {
+        // The interval wraps around the end of the hash space.
+        candidate > owner || candidate < next
+    }
+}
+
+/// Check whether `nsec3` authenticates the non-existence of `qname` under the zone's hashing parameters.
+pub fn nsec3_denies(nsec3_owner: &str, nsec3: &NSEC3, qname: &Name) -> bool {
+    let candidate = nsec3_hash(qname, nsec3.iterations(), nsec3.salt());
+    let next = BASE32HEX_NOPAD
+        .encode(nsec3.next_hashed_owner_name())
+        .to_ascii_lowercase();
+    covers(nsec3_owner, &next, &candidate)
+}
+
+/// Check whether `nsec`, owned by `owner`, authenticates the non-existence of `qname`: `qname` must fall
+/// strictly between `owner` and the NSEC's `next domain name` (with the same end-of-zone wraparound as
+/// NSEC3), in canonical DNS name ordering.
+pub fn nsec_denies(owner: &Name, nsec: &NSEC, qname: &Name) -> bool {
+    let owner = owner.to_lowercase();
+    let next = nsec.next_domain_name().to_lowercase();
+    let qname = qname.to_lowercase();
+    if owner < next {
+        owner < qname && qname < next
+    } else {
+        qname > owner || qname < next
+    }
+}
+
+/// Check whether `nsec`, owned by `owner`, authenticates a NODATA answer for `qname`/`qtype`: `owner` must
+/// match `qname` exactly (the name exists), and its type bitmap must list neither `qtype` itself nor
+/// `CNAME` (which would mean `qname` is aliased rather than genuinely missing that type).
+pub fn nsec_denies_type(owner: &Name, nsec: &NSEC, qname: &Name, qtype: RecordType) -> bool {
+    owner.to_lowercase() == qname.to_lowercase()
+        && !nsec.type_bit_maps().contains(&qtype)
+        && !nsec.type_bit_maps().contains(&RecordType::CNAME)
+}
+
+/// Check whether `nsec3`, whose hashed owner is `nsec3_owner`, authenticates a NODATA answer for
+/// `qname`/`qtype`: the hash must match `qname` exactly (the name exists), and its type bitmap must list
+/// neither `qtype` itself nor `CNAME`.
+pub fn nsec3_denies_type(nsec3_owner: &str, nsec3: &NSEC3, qname: &Name, qtype: RecordType) -> bool {
+    let candidate = nsec3_hash(qname, nsec3.iterations(), nsec3.salt());
+    nsec3_owner == candidate
+        && !nsec3.type_bit_maps().contains(&qtype)
+        && !nsec3.type_bit_maps().contains(&RecordType::CNAME)
+}
+
+// Whether `rec`'s NSEC/NSEC3 type bitmap proves `candidate` carries no DS record: the standard "insecure
+// delegation" proof (RFC 4035 section 5.2). Only an exact-owner NODATA proof counts — an interval-cover
+// proof just says `candidate` doesn't exist at all, which says nothing about a DS at a name that does.
+fn proves_no_ds(rec: &Record, candidate: &Name) -> bool {
+    match rec.rdata() {
+        RData::DNSSEC(DNSSECRData::NSEC(nsec)) => nsec_denies_type(rec.name(), nsec, candidate, RecordType::DS),
+        RData::DNSSEC(DNSSECRData::NSEC3(nsec3)) => {
+            let owner_hash = rec
+                .name()
+                .to_utf8()
+                .split('.')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            nsec3_denies_type(&owner_hash, nsec3, candidate, RecordType::DS)
+        }
+        _ => false,
+    }
+}
+
+// --- RRSIG cryptographic verification (RFC 4034 section 3.1.8.1) -------------------------------------
+
+// Canonicalize a `Record`'s owner name and RDATA the way an RRset's signed data is built: owner name in
+// canonical (lowercase, uncompressed) wire form, type, class, the RRSIG's `original_ttl` (not the record's
+// possibly-decremented TTL), RDATA length, then RDATA.
+fn emit_canonical_rr(buf: &mut Vec<u8>, record: &Record, original_ttl: u32) -> Option<()> {
+    let rtype: u16 = record.record_type().into();
+    let class: u16 = record.dns_class().into();
+    let mut rdata_buf = Vec::new();
+    {
+        let mut rdata_encoder = BinEncoder::new(&mut rdata_buf);
+        rdata_encoder.set_canonical_names(true);
+        record.rdata().emit(&mut rdata_encoder).ok()?;
+    }
+
+    let mut encoder = BinEncoder::new(buf);
+    encoder.set_canonical_names(true);
+    record.name().to_lowercase().emit(&mut encoder).ok()?;
+    rtype.emit(&mut encoder).ok()?;
+    class.emit(&mut encoder).ok()?;
+    original_ttl.emit(&mut encoder).ok()?;
+    (rdata_buf.len() as u16).emit(&mut encoder).ok()?;
+    encoder.emit_vec(&rdata_buf).ok()?;
+    Some(())
+}
+
+// Build the exact byte sequence an `RRSIG` signs: the RRSIG RDATA (minus the signature field itself),
+// followed by every RR in the covered RRset, each RR canonicalized and sorted in canonical RDATA order.
+fn signed_data(rrsig: &RRSIG, rrset: &[Record]) -> Option<Vec<u8>> {
+    let mut buf = Vec::new();
+    {
+        let mut encoder = BinEncoder::new(&mut buf);
+        encoder.set_canonical_names(true);
+        (u16::from(rrsig.type_covered())).emit(&mut encoder).ok()?;
+        (u8::from(rrsig.algorithm())).emit(&mut encoder).ok()?;
+        rrsig.num_labels().emit(&mut encoder).ok()?;
+        rrsig.original_ttl().emit(&mut encoder).ok()?;
+        rrsig.sig_expiration().emit(&mut encoder).ok()?;
+        rrsig.sig_inception().emit(&mut encoder).ok()?;
+        rrsig.key_tag().emit(&mut encoder).ok()?;
+        rrsig.signer_name().to_lowercase().emit(&mut encoder).ok()?;
+    }
+
+    let mut sorted: Vec<&Record> = rrset.iter().collect();
+    sorted.sort_by(|a, b| {
+        let mut ba = Vec::new();
+        let mut bb = Vec::new();
+        {
+            let mut ea = BinEncoder::new(&mut ba);
+            ea.set_canonical_names(true);
+            a.rdata().emit(&mut ea).ok();
+        }
+        {
+            let mut eb = BinEncoder::new(&mut bb);
+            eb.set_canonical_names(true);
+            b.rdata().emit(&mut eb).ok();
+        }
+        ba.cmp(&bb)
+    });
+    for record in sorted {
+        emit_canonical_rr(&mut buf, record, rrsig.original_ttl())?;
+    }
+    Some(buf)
+}
+
+// Split a DNSKEY RSA public key (RFC 3110) into its exponent and modulus.
+fn rsa_exponent_modulus(key_bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    match key_bytes.first()? {
+        0 => {
+            let elen = u16::from_be_bytes([*key_bytes.get(1)?, *key_bytes.get(2)?]) as usize;
+            Some((key_bytes.get(3..3 + elen)?, key_bytes.get(3 + elen..)?))
+        }
+        &len => {
+            let elen = len as usize;
+            Some((key_bytes.get(1..1 + elen)?, key_bytes.get(1 + elen..)?))
+        }
+    }
+}
+
+// RFC 4034 section 3.1.5: `sig_inception`/`sig_expiration` are serial numbers (mod 2^32) relative to `now`,
+// not plain integers, so validation keeps working correctly across the 32-bit rollover in 2106.
+fn serial_le(a: u32, b: u32) -> bool {
+    a == b || (b.wrapping_sub(a) as i32) > 0
+}
+
+// Whether `now` falls within `rrsig`'s inception/expiration window.
+fn rrsig_in_validity_window(rrsig: &RRSIG, now: u32) -> bool {
+    serial_le(rrsig.sig_inception(), now) && serial_le(now, rrsig.sig_expiration())
+}
+
+/// The current time as seconds since the Unix epoch, truncated to `u32` the way `RRSIG` inception/expiration
+/// timestamps are encoded on the wire.
+pub fn unix_now() -> u32 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// Cryptographically verify that `rrsig` is a valid signature by `key` over `rrset`, and that `now` falls
+/// within its inception/expiration window. Only RSASHA256 (8) and ECDSAP256SHA256 (13) are supported; any
+/// other algorithm fails closed.
+pub fn verify_rrsig(rrsig: &RRSIG, key: &DNSKEY, rrset: &[Record], now: u32) -> bool {
+    if !rrsig_in_validity_window(rrsig, now) {
+        return false;
+    }
+    let preimage = match signed_data(rrsig, rrset) {
+        Some(p) => p,
+        None => return false,
+    };
+    let sig = rrsig.sig();
+    match rrsig.algorithm() {
+        Algorithm::RSASHA256 => {
+            let (e, n) = match rsa_exponent_modulus(key.public_key()) {
+                Some(v) => v,
+                None => return false,
+            };
+            signature::RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, &preimage, sig)
+                .is_ok()
+        }
+        Algorithm::ECDSAP256SHA256 => {
+            let mut point = vec![0x04_u8];
+            point.extend_from_slice(key.public_key());
+            signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                .verify(&preimage, sig)
+                .is_ok()
+        }
+        _ => false,
+    }
+}
+
+// Whether `ds` is the digest of `key`, owned by `owner` (RFC 4034 section 5.1.4: hash(canonical owner name
+// || DNSKEY RDATA)).
+fn ds_matches(ds: &DS, owner: &Name, key: &DNSKEY) -> bool {
+    if ds.algorithm() != key.algorithm() {
+        return false;
+    }
+    let mut buf = Vec::new();
+    {
+        let mut encoder = BinEncoder::new(&mut buf);
+        encoder.set_canonical_names(true);
+        if owner.to_lowercase().emit(&mut encoder).is_err() {
+            return false;
+        }
+        if key.emit(&mut encoder).is_err() {
+            return false;
+        }
+    }
+    let got = match ds.digest_type() {
+        trust_dns_client::rr::dnssec::DigestType::SHA1 => digest(&SHA1, &buf).as_ref().to_vec(),
+        trust_dns_client::rr::dnssec::DigestType::SHA256 => digest(&SHA256, &buf).as_ref().to_vec(),
+        _ => return false,
+    };
+    got == ds.digest()
+}
+
+// Every record in `records` of type `rtype` owned by `owner`. Shared by the answer-section and
+// authority-section RRset lookups below.
+fn records_by_type<'a>(records: &'a [Record], owner: &Name, rtype: RecordType) -> Vec<&'a Record> {
+    records
+        .iter()
+        .filter(|r| r.record_type() == rtype && r.name() == owner)
+        .collect()
+}
+
+// The `RRSIG` in `records` covering `owner`'s RRset of type `covered`, if present.
+fn rrsig_for<'a>(records: &'a [Record], owner: &Name, covered: RecordType) -> Option<&'a RRSIG> {
+    records.iter().find_map(|r| match r.rdata() {
+        RData::DNSSEC(DNSSECRData::SIG(rrsig))
+            if r.name() == owner && rrsig.type_covered() == covered =>
+        {
+            Some(rrsig)
+        }
+        _ => None,
+    })
+}
+
+fn records_of<'a>(msg: &'a Message, owner: &Name, rtype: RecordType) -> Vec<&'a Record> {
+    records_by_type(msg.answers(), owner, rtype)
+}
+
+fn rrsig_of<'a>(msg: &'a Message, owner: &Name, covered: RecordType) -> Option<&'a RRSIG> {
+    rrsig_for(msg.answers(), owner, covered)
+}
+
+fn dnskeys_of<'a>(msg: &'a Message, owner: &Name) -> Vec<(&'a Record, &'a DNSKEY)> {
+    msg.answers()
+        .iter()
+        .filter_map(|r| match r.rdata() {
+            RData::DNSSEC(DNSSECRData::DNSKEY(key)) if r.name() == owner => Some((r, key)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn ds_of(msg: &Message, owner: &Name) -> Vec<DS> {
+    msg.answers()
+        .iter()
+        .filter_map(|r| match r.rdata() {
+            RData::DNSSEC(DNSSECRData::DS(ds)) if r.name() == owner => Some(ds.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn query_msg(name: Name, rtype: RecordType) -> Message {
+    let mut msg = Message::new();
+    msg.add_query(Query::query(name, rtype));
+    msg.edns_mut().set_dnssec_ok(true);
+    msg
+}
+
+// The names from (but not including) the root down to and including `qname`, e.g. for `www.example.com.`:
+// `["com.", "example.com.", "www.example.com."]`. Not all of these are necessarily zone cuts — most are
+// ordinary names inside their parent zone — `validate_chain` below only treats one as a new zone when a DS
+// actually exists for it.
+fn labels_to_root(qname: &Name) -> Vec<Name> {
+    let mut labels = vec![qname.clone()];
+    let mut cur = qname.clone();
+    while cur.num_labels() > 0 {
+        cur = cur.base_name();
+        if cur.num_labels() == 0 {
+            break;
+        }
+        labels.push(cur.clone());
+    }
+    labels.reverse();
+    labels
+}
+
+// Fetch `zone`'s DNSKEY RRset through `upstreams` and verify it's self-signed by a key matching one of
+// `trusted_ds`. Returns the verified DNSKEY set on success.
+async fn fetch_and_verify_dnskeys<L>(
+    upstreams: &Upstreams<L>,
+    tag: &L,
+    zone: &Name,
+    trusted_ds: &[DS],
+    now: u32,
+) -> Result<L, Option<Vec<DNSKEY>>>
+where
+    L: 'static + Display + Debug + Eq + std::hash::Hash + Send + Clone + Sync,
+{
+    let dnskey_msg = upstreams
+        .resolve(tag, &query_msg(zone.clone(), RecordType::DNSKEY))
+        .await?;
+    let dnskeys = dnskeys_of(&dnskey_msg, zone);
+    if dnskeys.is_empty() {
+        return Ok(None);
+    }
+    let rrsig = match rrsig_of(&dnskey_msg, zone, RecordType::DNSKEY) {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let records: Vec<Record> = dnskeys.iter().map(|(r, _)| (*r).clone()).collect();
+    let signed_by_trusted_key = dnskeys.iter().any(|(_, key)| {
+        trusted_ds.iter().any(|ds| ds_matches(ds, zone, key)) && verify_rrsig(rrsig, key, &records, now)
+    });
+    if !signed_by_trusted_key {
+        return Ok(None);
+    }
+    Ok(Some(dnskeys.into_iter().map(|(_, k)| k.clone()).collect()))
+}
+
+/// Verify, by querying `upstreams` under `tag`, that a chain of trust exists from `anchor`'s root down to
+/// the zone that actually owns `qname`. Each name between the root and `qname` is probed for a `DS` record.
+/// A name where one actually exists is a new zone cut, and has its `DNSKEY`s fetched and verified; a name
+/// where the `DS` query's authority section carries an `SOA` matching the zone we already hold keys for is
+/// just an ordinary non-apex name (like `www`) inside that zone, and needs no `DNSKEY` of its own. A name
+/// where the `DS` is absent and the `SOA` instead names a zone *other* than the one we hold keys for is an
+/// actual delegation point: it's only accepted as a legitimately unsigned ("insecure") delegation
+/// if that zone also hands back a signed NSEC/NSEC3 proving no `DS` exists there (RFC 4035 section 5.2) —
+/// otherwise the missing `DS` can't be told apart from a stripped one, and the chain is `Bogus`.
+pub async fn validate_chain<L>(
+    upstreams: &Upstreams<L>,
+    tag: &L,
+    qname: &Name,
+    anchor: &TrustAnchor,
+    now: u32,
+) -> Result<L, ChainStatus>
+where
+    L: 'static + Display + Debug + Eq + std::hash::Hash + Send + Clone + Sync,
+{
+    let mut trusted_ds = anchor.root_ds.clone();
+    let mut zone_name = Name::root();
+    let mut zone_keys =
+        match fetch_and_verify_dnskeys(upstreams, tag, &zone_name, &trusted_ds, now).await? {
+            Some(keys) => keys,
+            None => return Ok(ChainStatus::Bogus),
+        };
+
+    for candidate in labels_to_root(qname) {
+        let ds_msg = upstreams
+            .resolve(tag, &query_msg(candidate.clone(), RecordType::DS))
+            .await?;
+        let ds_records = ds_of(&ds_msg, &candidate);
+        if ds_records.is_empty() {
+            let authority = ds_msg.name_servers();
+            let is_delegation_point = match authority.iter().find(|r| r.record_type() == RecordType::SOA) {
+                Some(soa) => soa.name() != &zone_name,
+                // Neither a DS nor an SOA: an unexpected shape, not evidence of an ordinary name.
+                None => return Ok(ChainStatus::Bogus),
+            };
+            if !is_delegation_point {
+                // Ordinary name inside the zone we already hold keys for; its own RRset's RRSIG (if any)
+                // will be checked by the caller, so there's nothing more to verify at this label.
+                continue;
+            }
+
+            let denial = match authority.iter().find(|r| proves_no_ds(r, &candidate)) {
+                Some(r) => r,
+                None => return Ok(ChainStatus::Bogus),
+            };
+            let (owner, rtype) = (denial.name().clone(), denial.record_type());
+            let rrset: Vec<Record> = records_by_type(authority, &owner, rtype)
+                .into_iter()
+                .cloned()
+                .collect();
+            let rrsig = match rrsig_for(authority, &owner, rtype) {
+                Some(r) => r,
+                None => return Ok(ChainStatus::Bogus),
+            };
+            if !zone_keys.iter().any(|key| verify_rrsig(rrsig, key, &rrset, now)) {
+                return Ok(ChainStatus::Bogus);
+            }
+            return Ok(ChainStatus::Insecure);
+        }
+
+        let ds_rrsig = match rrsig_of(&ds_msg, &candidate, RecordType::DS) {
+            Some(r) => r,
+            None => return Ok(ChainStatus::Bogus),
+        };
+        let raw_ds_records: Vec<Record> = ds_msg
+            .answers()
+            .iter()
+            .filter(|r| r.record_type() == RecordType::DS && r.name() == &candidate)
+            .cloned()
+            .collect();
+        let ds_signed = zone_keys
+            .iter()
+            .any(|key| verify_rrsig(ds_rrsig, key, &raw_ds_records, now));
+        if !ds_signed {
+            return Ok(ChainStatus::Bogus);
+        }
+
+        trusted_ds = ds_records;
+        zone_name = candidate.clone();
+        zone_keys = match fetch_and_verify_dnskeys(upstreams, tag, &candidate, &trusted_ds, now).await? {
+            Some(keys) => keys,
+            None => return Ok(ChainStatus::Bogus),
+        };
+    }
+
+    Ok(ChainStatus::Secure(zone_keys))
+}
+
+/// Authenticate a positive answer. Every distinct `(owner, type)` RRset actually present in the answer
+/// section is verified, not just the one matching the literal question — a `CNAME` pointing elsewhere is
+/// itself a signed RRset that must check out, not merely the RRset at the chain's final name. Each RRset is
+/// validated against the chain of trust for *its own* owner name (fetched via `validate_chain`), rather than
+/// a single zone shared by the whole answer, so a `CNAME` aliasing into a different signed zone (e.g. a CDN)
+/// validates correctly instead of spuriously failing against the wrong zone's keys. An RRset whose zone is
+/// provably insecure is accepted unsigned; the overall result is `ProvablyInsecure` (not `Secure`) if any
+/// RRset needed that exception, since the AD bit must not be set unless everything validated as secure.
+pub async fn authenticate_answer<L>(
+    upstreams: &Upstreams<L>,
+    tag: &L,
+    msg: &Message,
+    anchor: &TrustAnchor,
+) -> Result<L, Validity>
+where
+    L: 'static + Display + Debug + Eq + std::hash::Hash + Send + Clone + Sync,
+{
+    let now = unix_now();
+    let mut seen = HashSet::new();
+    let mut chains: HashMap<Name, ChainStatus> = HashMap::new();
+    let mut any_insecure = false;
+    for rec in msg.answers() {
+        if rec.record_type() == RecordType::RRSIG {
+            continue;
+        }
+        let key = (rec.name().clone(), rec.record_type());
+        if !seen.insert(key.clone()) {
+            continue; // already verified this RRset via an earlier record in it
+        }
+        let (owner, rtype) = key;
+        if !chains.contains_key(&owner) {
+            let status = validate_chain(upstreams, tag, &owner, anchor, now).await?;
+            chains.insert(owner.clone(), status);
+        }
+        let zone_keys = match chains.get(&owner).expect("just inserted above") {
+            ChainStatus::Bogus => return Ok(Validity::Bogus),
+            ChainStatus::Insecure => {
+                any_insecure = true;
+                continue;
+            }
+            ChainStatus::Secure(keys) => keys,
+        };
+        let rrset: Vec<Record> = records_of(msg, &owner, rtype).into_iter().cloned().collect();
+        let rrsig = match rrsig_of(msg, &owner, rtype) {
+            Some(r) => r,
+            None => return Ok(Validity::Bogus),
+        };
+        if !zone_keys.iter().any(|key| verify_rrsig(rrsig, key, &rrset, now)) {
+            return Ok(Validity::Bogus);
+        }
+    }
+    Ok(if any_insecure {
+        Validity::ProvablyInsecure
+    } else {
+        Validity::Secure
+    })
+}
+
+/// Authenticate a negative (NODATA/NXDOMAIN) answer via the NSEC or NSEC3 records in the authority section.
+/// Each candidate NSEC/NSEC3 RRset (and any accompanying `SOA`) is only trusted to prove non-existence once
+/// its own `RRSIG` has been verified against a `validate_chain`-confirmed chain of trust for its owner name —
+/// an unsigned or forged denial proof is `Validity::Bogus`, not `InsecureDenial`. If the zone the denial
+/// would come from is itself provably insecure, there's no signed proof to find at all; that's reported as
+/// `Validity::ProvablyInsecure` rather than `Bogus`, since the answer is legitimately unauthenticatable.
+pub async fn authenticate_denial<L>(
+    upstreams: &Upstreams<L>,
+    tag: &L,
+    msg: &Message,
+    anchor: &TrustAnchor,
+) -> Result<L, Validity>
+where
+    L: 'static + Display + Debug + Eq + std::hash::Hash + Send + Clone + Sync,
+{
+    let query = match msg.queries().iter().next() {
+        Some(q) => q,
+        None => return Ok(Validity::Bogus),
+    };
+    let qname = query.name().clone();
+    let qtype = query.query_type();
+    let authority: Vec<Record> = msg.name_servers().to_vec();
+    let now = unix_now();
+
+    // The SOA and every NSEC/NSEC3 RRset accompanying a single negative answer almost always share the
+    // same zone apex, so cache each owner name's chain-of-trust walk rather than repeating it per RRset.
+    let mut chains: HashMap<Name, ChainStatus> = HashMap::new();
+    let mut insecure = false;
+
+    if let Some(soa) = authority.iter().find(|r| r.record_type() == RecordType::SOA) {
+        let owner = soa.name().clone();
+        if !chains.contains_key(&owner) {
+            let status = validate_chain(upstreams, tag, &owner, anchor, now).await?;
+            chains.insert(owner.clone(), status);
+        }
+        match chains.get(&owner).expect("just inserted above") {
+            ChainStatus::Bogus => return Ok(Validity::Bogus),
+            ChainStatus::Insecure => insecure = true,
+            ChainStatus::Secure(zone_keys) => {
+                let rrset: Vec<Record> = records_by_type(&authority, &owner, RecordType::SOA)
+                    .into_iter()
+                    .cloned()
+                    .collect();
+                let rrsig = match rrsig_for(&authority, &owner, RecordType::SOA) {
+                    Some(r) => r,
+                    None => return Ok(Validity::Bogus),
+                };
+                if !zone_keys.iter().any(|k| verify_rrsig(rrsig, k, &rrset, now)) {
+                    return Ok(Validity::Bogus);
+                }
+            }
+        }
+    }
+
+    let mut seen = HashSet::new();
+    for rec in &authority {
+        let (owner, rtype) = match rec.rdata() {
+            RData::DNSSEC(DNSSECRData::NSEC3(_)) => (rec.name().clone(), RecordType::NSEC3),
+            RData::DNSSEC(DNSSECRData::NSEC(_)) => (rec.name().clone(), RecordType::NSEC),
+            _ => continue,
+        };
+        if !seen.insert((owner.clone(), rtype)) {
+            continue; // already checked this RRset via an earlier record in it
+        }
+
+        let rrset: Vec<Record> = records_by_type(&authority, &owner, rtype)
+            .into_iter()
+            .cloned()
+            .collect();
+        let rrsig = match rrsig_for(&authority, &owner, rtype) {
+            Some(r) => r,
+            None => continue, // unsigned denial proof: can't be trusted, but isn't necessarily Bogus either
+        };
+        if !chains.contains_key(&owner) {
+            let status = validate_chain(upstreams, tag, &owner, anchor, now).await?;
+            chains.insert(owner.clone(), status);
+        }
+        let zone_keys = match chains.get(&owner).expect("just inserted above") {
+            ChainStatus::Secure(keys) => keys,
+            ChainStatus::Insecure => {
+                insecure = true;
+                continue;
+            }
+            ChainStatus::Bogus => continue,
+        };
+        if !zone_keys.iter().any(|k| verify_rrsig(rrsig, k, &rrset, now)) {
+            continue;
+        }
+
+        // Either proof shape authenticates the negative answer: an interval-cover proves NXDOMAIN (`qname`
+        // doesn't exist at all), while an exact-match-with-absent-type-bit proves NODATA (`qname` exists but
+        // has nothing of `qtype`) — the common case for e.g. an `AAAA` query against an IPv4-only name.
+        let denies = match rec.rdata() {
+            RData::DNSSEC(DNSSECRData::NSEC3(nsec3)) => {
+                let owner_hash = owner
+                    .to_utf8()
+                    .split('.')
+                    .next()
+                    .unwrap_or_default()
+                    .to_ascii_lowercase();
+                nsec3_denies(&owner_hash, nsec3, &qname)
+                    || nsec3_denies_type(&owner_hash, nsec3, &qname, qtype)
+            }
+            RData::DNSSEC(DNSSECRData::NSEC(nsec)) => {
+                nsec_denies(&owner, nsec, &qname) || nsec_denies_type(&owner, nsec, &qname, qtype)
+            }
+            _ => unreachable!("owner/rtype above is only populated for NSEC3 or NSEC records"),
+        };
+        if denies {
+            return Ok(Validity::InsecureDenial);
+        }
+    }
+    // No verified NSEC/NSEC3 record accompanying the negative answer proves anything. That's expected (not
+    // Bogus) when the zone that would hold the proof was itself confirmed provably insecure.
+    if insecure {
+        Ok(Validity::ProvablyInsecure)
+    } else {
+        Ok(Validity::Bogus)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_covers_basic_interval() {
+        assert!(covers("a", "m", "f"));
+        assert!(!covers("a", "m", "z"));
+        assert!(!covers("a", "m", "a"));
+    }
+
+    #[test]
+    fn test_covers_wraps_around_end_of_zone() {
+        // The last NSEC3 owner in a zone has `next` wrap back to the lexicographically-first owner.
+        assert!(covers("z", "b", "zz"));
+        assert!(covers("z", "b", "a"));
+        assert!(!covers("z", "b", "c"));
+    }
+
+    #[test]
+    fn test_nsec3_hash_is_deterministic() {
+        let name = Name::from_utf8("example.com").unwrap();
+        let a = nsec3_hash(&name, 1, &[0xAB, 0xCD]);
+        let b = nsec3_hash(&name, 1, &[0xAB, 0xCD]);
+        assert_eq!(a, b);
+        let different_salt = nsec3_hash(&name, 1, &[0xAB, 0xCE]);
+        assert_ne!(a, different_salt);
+    }
+
+    #[test]
+    fn test_nsec_denies_covers_qname_between_owner_and_next() {
+        let owner = Name::from_utf8("a.example.com").unwrap();
+        let next = Name::from_utf8("m.example.com").unwrap();
+        let nsec = NSEC::new(next, vec![]);
+        let covered = Name::from_utf8("f.example.com").unwrap();
+        let not_covered = Name::from_utf8("z.example.com").unwrap();
+        assert!(nsec_denies(&owner, &nsec, &covered));
+        assert!(!nsec_denies(&owner, &nsec, &not_covered));
+    }
+
+    #[test]
+    fn test_nsec_denies_type_requires_exact_owner_and_absent_bit() {
+        let owner = Name::from_utf8("example.com").unwrap();
+        let next = Name::from_utf8("www.example.com").unwrap();
+        let nsec = NSEC::new(next.clone(), vec![RecordType::A, RecordType::SOA]);
+        assert!(nsec_denies_type(&owner, &nsec, &owner, RecordType::AAAA));
+        assert!(!nsec_denies_type(&owner, &nsec, &owner, RecordType::A));
+        assert!(!nsec_denies_type(&owner, &nsec, &next, RecordType::AAAA));
+    }
+
+    #[test]
+    fn test_nsec_denies_type_rejects_aliased_name() {
+        let owner = Name::from_utf8("example.com").unwrap();
+        let next = Name::from_utf8("www.example.com").unwrap();
+        let nsec = NSEC::new(next, vec![RecordType::CNAME]);
+        assert!(!nsec_denies_type(&owner, &nsec, &owner, RecordType::AAAA));
+    }
+
+    #[test]
+    fn test_nsec3_denies_type_requires_exact_hash_and_absent_bit() {
+        let qname = Name::from_utf8("example.com").unwrap();
+        let salt = [0xAB, 0xCD];
+        let owner_hash = nsec3_hash(&qname, 1, &salt);
+        let next_hash = vec![0u8; 20];
+        let nsec3 = NSEC3::new(
+            trust_dns_client::rr::dnssec::rdata::Nsec3HashAlgorithm::SHA1,
+            false,
+            1,
+            salt.to_vec(),
+            next_hash,
+            vec![RecordType::A],
+        );
+        assert!(nsec3_denies_type(&owner_hash, &nsec3, &qname, RecordType::AAAA));
+        assert!(!nsec3_denies_type(&owner_hash, &nsec3, &qname, RecordType::A));
+        let other = Name::from_utf8("other.example.com").unwrap();
+        assert!(!nsec3_denies_type(&owner_hash, &nsec3, &other, RecordType::AAAA));
+    }
+
+    // --- End-to-end chain-of-trust tests --------------------------------------------------------------
+    //
+    // These hand-sign a small synthetic zone hierarchy (root -> "example." -> "www.example.") plus an
+    // "insecure." delegation proved unsigned via NSEC, drive `validate_chain`/`authenticate_answer`/
+    // `authenticate_denial` against it through a real `Upstreams<String>` backed by a local UDP server, and
+    // assert the right `ChainStatus`/`Validity` comes out — this is the code whose entire job is rejecting
+    // forged or unsigned DNSSEC data, so it's exercised cryptographically rather than only through its pure
+    // helpers above.
+
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+    };
+    use std::net::SocketAddr;
+    use tokio::net::UdpSocket;
+    use trust_dns_client::rr::{dnssec::DigestType, rdata::soa::SOA};
+    use trust_dns_proto::op::header::MessageType;
+
+    use crate::upstream::{Upstream, UpstreamKind};
+
+    const TEST_TTL: u32 = 3600;
+
+    // An inception/expiration window around the current time. Both ends stay well within 2^31 seconds of
+    // "now", since `serial_le` compares them to it in serial-number arithmetic.
+    fn test_validity_window() -> (u32, u32) {
+        let now = unix_now();
+        (now.wrapping_sub(86400), now.wrapping_add(30 * 86400))
+    }
+
+    // A generated ECDSAP256SHA256 key, plus the bits of it every signer/DS/key-tag helper below needs.
+    struct TestKey {
+        keypair: EcdsaKeyPair,
+        dnskey: DNSKEY,
+        key_tag: u16,
+    }
+
+    fn make_key() -> TestKey {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).expect("keygen");
+        let keypair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).expect("key parse");
+        // Strip the 0x04 uncompressed-point prefix: `verify_rrsig` re-adds it before handing the point to ring.
+        let public_key = keypair.public_key().as_ref()[1..].to_vec();
+        let dnskey = DNSKEY::new(true, true, false, Algorithm::ECDSAP256SHA256, public_key);
+        let key_tag = compute_key_tag(&dnskey);
+        TestKey { keypair, dnskey, key_tag }
+    }
+
+    // RFC 4034 Appendix B key tag algorithm, run over the same canonical RDATA bytes `ds_matches` hashes.
+    fn compute_key_tag(key: &DNSKEY) -> u16 {
+        let mut buf = Vec::new();
+        let mut encoder = BinEncoder::new(&mut buf);
+        key.emit(&mut encoder).expect("emit dnskey");
+        let mut ac: u32 = 0;
+        for (i, &b) in buf.iter().enumerate() {
+            ac += if i % 2 == 0 { (b as u32) << 8 } else { b as u32 };
+        }
+        ac += (ac >> 16) & 0xFFFF;
+        (ac & 0xFFFF) as u16
+    }
+
+    // The DS record a parent zone would publish for `owner`'s `key` (RFC 4034 section 5.1.4).
+    fn ds_for(owner: &Name, key: &TestKey) -> DS {
+        let mut buf = Vec::new();
+        let mut encoder = BinEncoder::new(&mut buf);
+        encoder.set_canonical_names(true);
+        owner.to_lowercase().emit(&mut encoder).expect("emit owner");
+        key.dnskey.emit(&mut encoder).expect("emit dnskey");
+        let digest_bytes = digest(&SHA256, &buf).as_ref().to_vec();
+        DS::new(key.key_tag, Algorithm::ECDSAP256SHA256, DigestType::SHA256, digest_bytes)
+    }
+
+    // Sign `rrset` (owned by `owner`) as `signer_name`'s `key`, the same way `verify_rrsig` expects to check it.
+    fn sign_rrset(
+        key: &TestKey,
+        signer_name: &Name,
+        owner: &Name,
+        rtype: RecordType,
+        rrset: &[Record],
+    ) -> RRSIG {
+        let rng = SystemRandom::new();
+        let (inception, expiration) = test_validity_window();
+        let unsigned = RRSIG::new(
+            rtype,
+            Algorithm::ECDSAP256SHA256,
+            owner.num_labels(),
+            TEST_TTL,
+            expiration,
+            inception,
+            key.key_tag,
+            signer_name.clone(),
+            vec![],
+        );
+        let preimage = signed_data(&unsigned, rrset).expect("canonicalize test rrset");
+        let sig = key.keypair.sign(&rng, &preimage).expect("sign").as_ref().to_vec();
+        RRSIG::new(
+            rtype,
+            Algorithm::ECDSAP256SHA256,
+            owner.num_labels(),
+            TEST_TTL,
+            expiration,
+            inception,
+            key.key_tag,
+            signer_name.clone(),
+            sig,
+        )
+    }
+
+    fn rr(owner: &Name, rdata: RData) -> Record {
+        Record::from_rdata(owner.clone(), TEST_TTL, rdata)
+    }
+
+    fn rrsig_rr(owner: &Name, rrsig: RRSIG) -> Record {
+        rr(owner, RData::DNSSEC(DNSSECRData::SIG(rrsig)))
+    }
+
+    fn soa_for(owner: &Name) -> SOA {
+        SOA::new(owner.clone(), owner.clone(), 1, 3600, 600, 86400, 3600)
+    }
+
+    // Rebuild `rrsig` with the same fields but a corrupted signature, to assert a forged/corrupted RRSIG
+    // is rejected rather than accidentally accepted.
+    fn tamper(rrsig: &RRSIG) -> RRSIG {
+        let mut sig = rrsig.sig().to_vec();
+        sig[0] ^= 0xFF;
+        RRSIG::new(
+            rrsig.type_covered(),
+            rrsig.algorithm(),
+            rrsig.num_labels(),
+            rrsig.original_ttl(),
+            rrsig.sig_expiration(),
+            rrsig.sig_inception(),
+            rrsig.key_tag(),
+            rrsig.signer_name().clone(),
+            sig,
+        )
+    }
+
+    // The hand-signed zone hierarchy every test below drives through `validate_chain`/`authenticate_answer`/
+    // `authenticate_denial`: a root zone, a securely-delegated child "example.", an ordinary name "www.example."
+    // inside it, and a sibling delegation "insecure." that the root proves (via NSEC) carries no DS at all.
+    struct World {
+        anchor: TrustAnchor,
+        root: Name,
+        example: Name,
+        www_example: Name,
+        insecure: Name,
+        root_key: TestKey,
+        example_key: TestKey,
+        responses: HashMap<(Name, RecordType), Message>,
+    }
+
+    fn build_world() -> World {
+        let root = Name::root();
+        let example = Name::from_utf8("example.").unwrap();
+        let www_example = Name::from_utf8("www.example.").unwrap();
+        let insecure = Name::from_utf8("insecure.").unwrap();
+
+        let root_key = make_key();
+        let example_key = make_key();
+        let anchor = TrustAnchor {
+            root_ds: vec![ds_for(&root, &root_key)],
+        };
+
+        let mut responses = HashMap::new();
+
+        // DNSKEY@root: self-signed by the root key the trust anchor's DS matches.
+        let root_dnskey = rr(&root, RData::DNSSEC(DNSSECRData::DNSKEY(root_key.dnskey.clone())));
+        let root_dnskey_rrsig = sign_rrset(&root_key, &root, &root, RecordType::DNSKEY, &[root_dnskey.clone()]);
+        let mut root_dnskey_msg = Message::new();
+        root_dnskey_msg.add_answer(root_dnskey.clone());
+        root_dnskey_msg.add_answer(rrsig_rr(&root, root_dnskey_rrsig));
+        responses.insert((root.clone(), RecordType::DNSKEY), root_dnskey_msg);
+
+        // DS@root: root has no parent, so just an ordinary (unsigned) SOA telling the walk to stop here.
+        let mut root_ds_msg = Message::new();
+        root_ds_msg.add_name_server(rr(&root, RData::SOA(soa_for(&root))));
+        responses.insert((root.clone(), RecordType::DS), root_ds_msg);
+
+        // DS@example.: a real delegation, signed by the root key.
+        let example_ds = rr(&example, RData::DNSSEC(DNSSECRData::DS(ds_for(&example, &example_key))));
+        let example_ds_rrsig = sign_rrset(&root_key, &root, &example, RecordType::DS, &[example_ds.clone()]);
+        let mut example_ds_msg = Message::new();
+        example_ds_msg.add_answer(example_ds);
+        example_ds_msg.add_answer(rrsig_rr(&example, example_ds_rrsig));
+        responses.insert((example.clone(), RecordType::DS), example_ds_msg);
+
+        // DNSKEY@example.: self-signed by the example key the DS above matches.
+        let example_dnskey = rr(&example, RData::DNSSEC(DNSSECRData::DNSKEY(example_key.dnskey.clone())));
+        let example_dnskey_rrsig =
+            sign_rrset(&example_key, &example, &example, RecordType::DNSKEY, &[example_dnskey.clone()]);
+        let mut example_dnskey_msg = Message::new();
+        example_dnskey_msg.add_answer(example_dnskey);
+        example_dnskey_msg.add_answer(rrsig_rr(&example, example_dnskey_rrsig));
+        responses.insert((example.clone(), RecordType::DNSKEY), example_dnskey_msg);
+
+        // DS@www.example.: no DS, and the SOA names the zone we already hold keys for -- an ordinary
+        // non-apex name, not a further delegation.
+        let mut www_ds_msg = Message::new();
+        www_ds_msg.add_name_server(rr(&example, RData::SOA(soa_for(&example))));
+        responses.insert((www_example.clone(), RecordType::DS), www_ds_msg);
+
+        // DS@insecure.: no DS, and the SOA names a *different* zone -- a real delegation point, proved
+        // insecure by a root-signed NSEC whose type bitmap carries no DS bit.
+        let insecure_nsec = rr(
+            &insecure,
+            RData::DNSSEC(DNSSECRData::NSEC(NSEC::new(
+                Name::from_utf8("zzzinsecure.").unwrap(),
+                vec![RecordType::SOA, RecordType::NS],
+            ))),
+        );
+        let insecure_nsec_rrsig =
+            sign_rrset(&root_key, &root, &insecure, RecordType::NSEC, &[insecure_nsec.clone()]);
+        let mut insecure_ds_msg = Message::new();
+        insecure_ds_msg.add_name_server(rr(&insecure, RData::SOA(soa_for(&insecure))));
+        insecure_ds_msg.add_name_server(insecure_nsec);
+        insecure_ds_msg.add_name_server(rrsig_rr(&insecure, insecure_nsec_rrsig));
+        responses.insert((insecure.clone(), RecordType::DS), insecure_ds_msg);
+
+        World {
+            anchor,
+            root,
+            example,
+            www_example,
+            insecure,
+            root_key,
+            example_key,
+            responses,
+        }
+    }
+
+    // Answer every query in `responses` verbatim (matched by name+type) over a local UDP socket, the way a
+    // real authoritative/recursive upstream would -- `validate_chain` only ever talks to `Upstreams` this way.
+    async fn serve(socket: UdpSocket, responses: HashMap<(Name, RecordType), Message>) {
+        let mut buf = vec![0_u8; 4096];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(v) => v,
+                Err(_) => return,
+            };
+            let req = match Message::from_vec(&buf[..len]) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            let mut resp = req
+                .queries()
+                .iter()
+                .next()
+                .and_then(|q| responses.get(&(q.name().clone(), q.query_type())))
+                .cloned()
+                .unwrap_or_else(Message::new);
+            resp.set_id(req.id());
+            resp.set_message_type(MessageType::Response);
+            let _ = socket.send_to(&resp.to_vec().unwrap(), peer).await;
+        }
+    }
+
+    async fn mock_upstreams(responses: HashMap<(Name, RecordType), Message>) -> (Upstreams<String>, String) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = socket.local_addr().unwrap();
+        tokio::spawn(serve(socket, responses));
+        let tag = "mock".to_string();
+        let upstreams = Upstreams::new(
+            vec![Upstream {
+                timeout: 5,
+                method: UpstreamKind::Udp(addr),
+                tag: tag.clone(),
+                proxy: None,
+                proxy_auth: None,
+            }],
+            0,
+        )
+        .await
+        .unwrap();
+        (upstreams, tag)
+    }
+
+    #[tokio::test]
+    async fn test_validate_chain_secure_through_delegation_and_ordinary_name() {
+        let world = build_world();
+        let (upstreams, tag) = mock_upstreams(world.responses.clone()).await;
+        let status = validate_chain(&upstreams, &tag, &world.www_example, &world.anchor, unix_now())
+            .await
+            .unwrap();
+        match status {
+            ChainStatus::Secure(keys) => assert_eq!(keys, vec![world.example_key.dnskey.clone()]),
+            other => panic!("expected ChainStatus::Secure, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_chain_insecure_delegation_proved_by_nsec() {
+        let world = build_world();
+        let (upstreams, tag) = mock_upstreams(world.responses.clone()).await;
+        let status = validate_chain(&upstreams, &tag, &world.insecure, &world.anchor, unix_now())
+            .await
+            .unwrap();
+        assert!(matches!(status, ChainStatus::Insecure), "expected Insecure, got {:?}", status);
+    }
+
+    #[tokio::test]
+    async fn test_validate_chain_bogus_on_tampered_dnskey_signature() {
+        let mut world = build_world();
+        // Flip a byte of the root DNSKEY RRSIG's signature: the chain must not come back Secure over a
+        // signature that doesn't actually verify.
+        let original = world.responses[&(world.root.clone(), RecordType::DNSKEY)].clone();
+        let (mut answers, mut rrsig) = (Vec::new(), None);
+        for r in original.answers() {
+            match r.rdata() {
+                RData::DNSSEC(DNSSECRData::SIG(sig)) => rrsig = Some(sig.clone()),
+                _ => answers.push(r.clone()),
+            }
+        }
+        let mut tampered = Message::new();
+        for r in answers {
+            tampered.add_answer(r);
+        }
+        tampered.add_answer(rrsig_rr(&world.root, tamper(&rrsig.expect("dnskey rrsig present"))));
+        world
+            .responses
+            .insert((world.root.clone(), RecordType::DNSKEY), tampered);
+
+        let (upstreams, tag) = mock_upstreams(world.responses.clone()).await;
+        let status = validate_chain(&upstreams, &tag, &world.root, &world.anchor, unix_now())
+            .await
+            .unwrap();
+        assert!(matches!(status, ChainStatus::Bogus), "expected Bogus, got {:?}", status);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_answer_secure() {
+        let world = build_world();
+        let (upstreams, tag) = mock_upstreams(world.responses.clone()).await;
+
+        let a = rr(&world.www_example, RData::A("93.184.216.34".parse().unwrap()));
+        let a_rrsig = sign_rrset(&world.example_key, &world.example, &world.www_example, RecordType::A, &[a.clone()]);
+        let mut msg = Message::new();
+        msg.add_answer(a);
+        msg.add_answer(rrsig_rr(&world.www_example, a_rrsig));
+
+        let validity = authenticate_answer(&upstreams, &tag, &msg, &world.anchor).await.unwrap();
+        assert_eq!(validity, Validity::Secure);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_answer_bogus_on_forged_answer_signature() {
+        let world = build_world();
+        let (upstreams, tag) = mock_upstreams(world.responses.clone()).await;
+
+        let a = rr(&world.www_example, RData::A("93.184.216.34".parse().unwrap()));
+        let a_rrsig = sign_rrset(&world.example_key, &world.example, &world.www_example, RecordType::A, &[a.clone()]);
+        let mut msg = Message::new();
+        msg.add_answer(a);
+        msg.add_answer(rrsig_rr(&world.www_example, tamper(&a_rrsig)));
+
+        let validity = authenticate_answer(&upstreams, &tag, &msg, &world.anchor).await.unwrap();
+        assert_eq!(validity, Validity::Bogus);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_answer_provably_insecure_for_unsigned_delegation() {
+        let world = build_world();
+        let (upstreams, tag) = mock_upstreams(world.responses.clone()).await;
+
+        // "insecure." carries no signature at all -- that's the whole point of a zone proved unsigned.
+        let mut msg = Message::new();
+        msg.add_answer(rr(&world.insecure, RData::A("198.51.100.1".parse().unwrap())));
+
+        let validity = authenticate_answer(&upstreams, &tag, &msg, &world.anchor).await.unwrap();
+        assert_eq!(validity, Validity::ProvablyInsecure);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_denial_nxdomain_secure() {
+        let world = build_world();
+        let (upstreams, tag) = mock_upstreams(world.responses.clone()).await;
+
+        let soa = rr(&world.root, RData::SOA(soa_for(&world.root)));
+        let soa_rrsig = sign_rrset(&world.root_key, &world.root, &world.root, RecordType::SOA, &[soa.clone()]);
+        let nsec = rr(
+            &world.root,
+            RData::DNSSEC(DNSSECRData::NSEC(NSEC::new(
+                Name::from_utf8("z.").unwrap(),
+                vec![RecordType::SOA, RecordType::NS],
+            ))),
+        );
+        let nsec_rrsig = sign_rrset(&world.root_key, &world.root, &world.root, RecordType::NSEC, &[nsec.clone()]);
+
+        let mut msg = Message::new();
+        msg.add_query(Query::query(Name::from_utf8("m.").unwrap(), RecordType::AAAA));
+        msg.add_name_server(soa);
+        msg.add_name_server(rrsig_rr(&world.root, soa_rrsig));
+        msg.add_name_server(nsec);
+        msg.add_name_server(rrsig_rr(&world.root, nsec_rrsig));
+
+        let validity = authenticate_denial(&upstreams, &tag, &msg, &world.anchor).await.unwrap();
+        assert_eq!(validity, Validity::InsecureDenial);
+    }
+}
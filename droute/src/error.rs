@@ -0,0 +1,57 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Error and Result types shared across `droute`.
+
+use std::fmt::{Debug, Display};
+use thiserror::Error;
+
+/// A `Result` that is parametrized over the tag label type `L` used throughout `droute`.
+pub type Result<L, T> = std::result::Result<T, DrouteError<L>>;
+
+/// Errors that can occur while building or running a `Router`.
+#[derive(Debug, Error)]
+pub enum DrouteError<L: Display + Debug> {
+    /// The tag `L` referenced by a rule or the default tag doesn't match any configured upstream.
+    #[error("the upstream tag `{0}` is not defined")]
+    MissingTag(L),
+
+    /// Two or more upstreams share the same tag, which makes routing ambiguous.
+    #[error("duplicate upstream tag `{0}` found while validating upstreams")]
+    DuplicateTag(L),
+
+    /// A rule's `dst` is used by a synthesizing rule (a virtual, non-upstream tag) and also by a normal,
+    /// upstream-routing rule, so it's ambiguous which behavior a query matching both should get.
+    #[error("tag `{0}` is used by both a synthesizing rule and a normal routing rule")]
+    AmbiguousSynthesizeTag(L),
+
+    /// Upstream `{0}` is a plain-UDP upstream with a SOCKS5 `proxy` configured. UDP traffic isn't tunneled
+    /// through the proxy (only the truncation-triggered TCP retry would be), so the query would reach the
+    /// real resolver directly, defeating the point of configuring a proxy at all.
+    #[error("upstream `{0}` is UDP with a proxy configured, but UDP cannot be tunneled through a SOCKS5 proxy here; use a Tcp, Tls, or Https upstream instead")]
+    ProxiedUdpUnsupported(L),
+
+    /// No upstreams were configured at all.
+    #[error("no upstreams are configured")]
+    EmptyUpstreams,
+
+    /// The TLS/TCP connection to an upstream could not be established or used.
+    #[error("I/O error talking to upstream: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// Failed to parse or build a DNS message.
+    #[error("DNS protocol error: {0}")]
+    Proto(#[from] trust_dns_proto::error::ProtoError),
+}
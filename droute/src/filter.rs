@@ -0,0 +1,190 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! `Filter` turns a query name into an upstream tag, using the routing `Rule`s configured by the user.
+
+use crate::{
+    blackhole::Synthesis,
+    error::{DrouteError, Result},
+    matcher::Matcher,
+};
+use ipnet::IpNet;
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+    hash::Hash,
+};
+
+/// Where a post-resolution probe, if its trial answer's addresses don't land inside `nets`, should
+/// re-route to instead.
+pub struct Probe<L> {
+    /// CIDR networks the probe's trial answer is checked against.
+    pub nets: Vec<IpNet>,
+    /// Upstream tag to re-query when the trial answer's addresses fall outside `nets`.
+    pub trusted: L,
+}
+
+/// A single routing rule: queries whose name matches one of `domains` are routed to `dst`. If `probe` is
+/// set, `dst` is queried first as a trial, and the final answer instead comes from `probe.trusted` whenever
+/// the trial answer's addresses don't fall inside `probe.nets` (the "local resolver returned something
+/// outside the expected range, re-query the trusted upstream" anti-pollution pattern). If `synthesize` is
+/// set, `dst` isn't an upstream at all: the query is answered locally and neither `dst` nor `probe` is used.
+pub struct Rule<L> {
+    /// Domain suffixes this rule applies to.
+    pub domains: Vec<String>,
+    /// Upstream tag queries on this rule are routed to (the probe upstream, if `probe` is set). Acts purely
+    /// as this rule's identity when `synthesize` is set, and need not name a configured upstream in that case.
+    pub dst: L,
+    /// Optional post-resolution probe gating whether `dst`'s answer is trusted as-is.
+    pub probe: Option<Probe<L>>,
+    /// When set, queries matching this rule are answered locally instead of being routed anywhere.
+    pub synthesize: Option<Synthesis>,
+}
+
+/// Routes query names to upstream tags according to the configured `Rule`s, falling back to `default_tag`.
+pub struct Filter<L, M: Matcher<Label = L>> {
+    default_tag: L,
+    matcher: M,
+    dsts: Vec<L>,
+    probes: HashMap<L, Probe<L>>,
+    synth: HashMap<L, Synthesis>,
+}
+
+impl<L, M: Matcher<Label = L>> Filter<L, M>
+where
+    L: Eq + Hash + Clone + Display + Debug,
+{
+    /// Build a `Filter` from the user's routing rules.
+    pub async fn new(default_tag: L, rules: Vec<Rule<L>>) -> Result<L, Self> {
+        let mut matcher = M::default();
+        let mut dsts = Vec::with_capacity(rules.len());
+        let mut probes = HashMap::new();
+        let mut synth = HashMap::new();
+        for rule in rules {
+            for domain in &rule.domains {
+                matcher.insert(domain, rule.dst.clone());
+            }
+            if let Some(synthesis) = rule.synthesize {
+                // A synthesizing rule's `dst` is a virtual tag, not a real upstream: it must not be checked
+                // for existence against the configured `Upstreams`, nor collide with a normal rule's tag.
+                if dsts.contains(&rule.dst) {
+                    return Err(DrouteError::AmbiguousSynthesizeTag(rule.dst));
+                }
+                synth.insert(rule.dst, synthesis);
+            } else {
+                if synth.contains_key(&rule.dst) {
+                    return Err(DrouteError::AmbiguousSynthesizeTag(rule.dst));
+                }
+                dsts.push(rule.dst.clone());
+                if let Some(probe) = rule.probe {
+                    dsts.push(probe.trusted.clone());
+                    probes.insert(rule.dst, probe);
+                }
+            }
+        }
+        Ok(Self {
+            default_tag,
+            matcher,
+            dsts,
+            probes,
+            synth,
+        })
+    }
+
+    /// The default tag used when no rule matches a query.
+    pub fn default_tag(&self) -> L {
+        self.default_tag.clone()
+    }
+
+    /// All upstream tags referenced by any rule, used by `Router::check` to validate they all exist.
+    pub fn get_dsts(&self) -> &[L] {
+        &self.dsts
+    }
+
+    /// The upstream tag a query name routes to.
+    pub fn get_upstream(&self, qname: &str) -> L {
+        self.matcher.matches(qname).unwrap_or_else(|| self.default_tag())
+    }
+
+    /// The probe configured for `tag`, if routing through it requires a trial resolution first.
+    pub fn get_probe(&self, tag: &L) -> Option<&Probe<L>> {
+        self.probes.get(tag)
+    }
+
+    /// The local synthesis configured for `tag`, if the query should be answered without contacting any
+    /// upstream at all.
+    pub fn get_synthesis(&self, tag: &L) -> Option<&Synthesis> {
+        self.synth.get(tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A `Matcher` that never matches anything; these tests only exercise `Filter::new`'s own validation.
+    #[derive(Default)]
+    struct NoopMatcher;
+    impl Matcher for NoopMatcher {
+        type Label = String;
+        fn insert(&mut self, _key: &str, _label: String) {}
+        fn matches(&self, _qname: &str) -> Option<String> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn test_synthesize_tag_colliding_with_normal_rule_is_rejected() {
+        let rules = vec![
+            Rule {
+                domains: vec!["ads.example.com".into()],
+                dst: "blocked".into(),
+                probe: None,
+                synthesize: Some(Synthesis::NxDomain),
+            },
+            Rule {
+                domains: vec!["example.com".into()],
+                dst: "blocked".into(),
+                probe: None,
+                synthesize: None,
+            },
+        ];
+        let err = Filter::<String, NoopMatcher>::new("default".into(), rules)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DrouteError::AmbiguousSynthesizeTag(tag) if tag == "blocked"));
+    }
+
+    #[tokio::test]
+    async fn test_distinct_tags_are_accepted() {
+        let rules = vec![
+            Rule {
+                domains: vec!["ads.example.com".into()],
+                dst: "blocked".into(),
+                probe: None,
+                synthesize: Some(Synthesis::NxDomain),
+            },
+            Rule {
+                domains: vec!["example.com".into()],
+                dst: "upstream".into(),
+                probe: None,
+                synthesize: None,
+            },
+        ];
+        assert!(Filter::<String, NoopMatcher>::new("default".into(), rules)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Matchers decide which tag a query (or, for `IpCidrMatcher`, an already-resolved answer) belongs to.
+
+use ipnet::IpNet;
+use std::net::IpAddr;
+use trust_dns_client::{op::Message, rr::RData};
+
+/// Matches a query name against some criterion, yielding the tag of the rule it belongs to. `Domain` in the
+/// `dmatcher` crate is the usual implementation, matching on domain suffix.
+pub trait Matcher: Default {
+    /// The tag type rules route to.
+    type Label;
+
+    /// Register `key` (e.g. a domain suffix) as belonging to `label`.
+    fn insert(&mut self, key: &str, label: Self::Label);
+
+    /// Return the label the query name `qname` matches, if any.
+    fn matches(&self, qname: &str) -> Option<Self::Label>;
+}
+
+/// Matches on whether any `A`/`AAAA` address in a resolved `Message`'s answer section falls inside a
+/// configured set of CIDR networks (e.g. GeoIP ranges). Used post-resolution, unlike `Matcher`, since it
+/// needs an actual answer rather than just a query name.
+pub struct IpCidrMatcher {
+    nets: Vec<IpNet>,
+}
+
+impl IpCidrMatcher {
+    /// Build a matcher from a set of CIDR networks, e.g. `114.114.0.0/16` or `2400::/12`.
+    pub fn new(nets: Vec<IpNet>) -> Self {
+        Self { nets }
+    }
+
+    /// Whether any `A`/`AAAA` record in `msg`'s answer section falls inside one of the configured networks.
+    pub fn contains_answer(&self, msg: &Message) -> bool {
+        msg.answers().iter().any(|r| {
+            let ip: Option<IpAddr> = match r.rdata() {
+                RData::A(addr) => Some((*addr).into()),
+                RData::AAAA(addr) => Some((*addr).into()),
+                _ => None,
+            };
+            ip.map_or(false, |ip| self.nets.iter().any(|net| net.contains(&ip)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns_client::rr::Name;
+
+    fn msg_with_a(ip: &str) -> Message {
+        let mut msg = Message::new();
+        msg.add_answer(trust_dns_client::rr::Record::from_rdata(
+            Name::from_utf8("example.com").unwrap(),
+            60,
+            RData::A(ip.parse().unwrap()),
+        ));
+        msg
+    }
+
+    #[test]
+    fn test_contains_answer_matches_in_range() {
+        let matcher = IpCidrMatcher::new(vec!["114.114.0.0/16".parse().unwrap()]);
+        assert!(matcher.contains_answer(&msg_with_a("114.114.114.114")));
+    }
+
+    #[test]
+    fn test_contains_answer_rejects_out_of_range() {
+        let matcher = IpCidrMatcher::new(vec!["114.114.0.0/16".parse().unwrap()]);
+        assert!(!matcher.contains_answer(&msg_with_a("8.8.8.8")));
+    }
+
+    #[test]
+    fn test_contains_answer_empty_answer_section() {
+        let matcher = IpCidrMatcher::new(vec!["0.0.0.0/0".parse().unwrap()]);
+        assert!(!matcher.contains_answer(&Message::new()));
+    }
+}
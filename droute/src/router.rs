@@ -15,17 +15,20 @@
 
 //! Router is the core concept of `droute`.
 
+pub mod blackhole;
+pub mod dnssec;
 pub mod filter;
 pub mod matcher;
 pub mod upstream;
 
 use self::{
+    blackhole::{synthesize, SoaParams, Synthesis},
+    dnssec::{TrustAnchor, Validity},
     filter::{Filter, Rule},
-    matcher::Matcher,
+    matcher::{IpCidrMatcher, Matcher},
     upstream::{Upstream, Upstreams},
 };
 use crate::error::Result;
-use lazy_static::lazy_static;
 use log::warn;
 use std::{
     fmt::{Debug, Display},
@@ -33,28 +36,9 @@ use std::{
 };
 use trust_dns_client::{
     op::{Message, ResponseCode},
-    rr::{rdata::soa::SOA, record_data::RData, resource::Record, Name, RecordType},
+    rr::RecordType,
 };
 
-// Maximum TTL as defined in https://tools.ietf.org/html/rfc2181, 2147483647
-//   Setting this to a value of 1 day, in seconds
-pub(self) const MAX_TTL: u32 = 86400_u32;
-
-// Data from smartdns. https://github.com/pymumu/smartdns/blob/42b3e98b2a3ca90ea548f8cb5ed19a3da6011b74/src/dns_server.c#L651
-lazy_static! {
-    static ref SOA_RDATA: RData = {
-        RData::SOA(SOA::new(
-            Name::from_utf8("a.gtld-servers.net").unwrap(),
-            Name::from_utf8("nstld.verisign-grs.com").unwrap(),
-            1800,
-            1800,
-            900,
-            604800,
-            86400,
-        ))
-    };
-}
-
 /// Router implementation.
 /// `'static + Send + Sync` is required for async usages.
 /// `Display + Debug` is required for Error formatting implementation (It is intuitive for you to have your label readable).
@@ -62,6 +46,8 @@ lazy_static! {
 pub struct Router<L, M> {
     filter: Filter<L, M>,
     disable_ipv6: bool,
+    dnssec: bool,
+    trust_anchor: TrustAnchor,
     upstreams: Upstreams<L>,
 }
 
@@ -73,6 +59,7 @@ where
     pub async fn new(
         upstreams: Vec<Upstream<L>>,
         disable_ipv6: bool,
+        dnssec: bool,
         cache_size: usize,
         default_tag: L,
         rules: Vec<Rule<L>>,
@@ -80,6 +67,8 @@ where
         let filter = Filter::new(default_tag, rules).await?;
         let router = Self {
             disable_ipv6,
+            dnssec,
+            trust_anchor: TrustAnchor::root(),
             upstreams: Upstreams::new(upstreams, cache_size).await?,
             filter,
         };
@@ -103,28 +92,93 @@ where
         let tag = if msg.query_count() == 1 {
             let q = msg.queries().iter().next().unwrap(); // Safe unwrap here because query_count == 1
             if (q.query_type() == RecordType::AAAA) && (self.disable_ipv6) {
-                // If `disable_ipv6` has been set, return immediately SOA.
-                return Ok({
-                    let r = Record::from_rdata(q.name().clone(), MAX_TTL, SOA_RDATA.clone());
-                    // We can't add record to authority section but somehow it works
-                    msg.add_additional(r);
-                    msg
-                });
-            } else {
-                self.filter.get_upstream(q.name().to_utf8().as_str())
+                // If `disable_ipv6` has been set, synthesize a NOERROR/SOA answer rather than asking upstream.
+                return Ok(synthesize(msg, &Synthesis::Soa(SoaParams::default())));
             }
+            let tag = self.filter.get_upstream(q.name().to_utf8().as_str());
+            if let Some(synthesis) = self.filter.get_synthesis(&tag) {
+                return Ok(synthesize(msg, synthesis));
+            }
+            tag
         } else {
             warn!("DNS message contains multiple/zero querie(s), using default_tag to route. IPv6 disable functionality is NOT taking effect.");
             self.filter.default_tag()
         };
-        Ok(match self.upstreams.resolve(tag, &msg).await {
-            Ok(m) => m,
+
+        if self.dnssec {
+            // Ask upstream for the RRSIG/NSEC(3) records we need to validate the answer.
+            msg.edns_mut().set_dnssec_ok(true);
+        }
+
+        let (answered_by, mut resp) = match self.resolve_with_probe(&tag, &msg).await {
+            Ok(v) => v,
             Err(e) => {
                 // Catch all server failure here and return server fail
                 warn!("Upstream encountered error: {}, returning SERVFAIL", e);
-                Message::error_msg(id, op_code, ResponseCode::ServFail)
+                return Ok(Message::error_msg(id, op_code, ResponseCode::ServFail));
+            }
+        };
+
+        if self.dnssec {
+            match self.validate(&answered_by, &resp).await {
+                Validity::Secure => resp.set_authentic_data(true),
+                Validity::InsecureDenial => resp.set_authentic_data(true),
+                // A provably unsigned zone: accept the answer as-is, but the AD bit must not be set since
+                // nothing was actually authenticated.
+                Validity::ProvablyInsecure => {}
+                Validity::Bogus => {
+                    warn!("DNSSEC validation failed for {}, returning SERVFAIL", id);
+                    return Ok(Message::error_msg(id, op_code, ResponseCode::ServFail));
+                }
+            }
+        }
+
+        Ok(resp)
+    }
+
+    // Resolve `tag`, re-routing to the rule's probe-trusted upstream if a probe is configured for `tag`,
+    // the trial answer's addresses don't fall inside the probe's configured networks, and the query is for
+    // an address record (A/AAAA) to begin with. Non-address queries and non-NOERROR trial answers have
+    // nothing for `IpCidrMatcher` to usefully check, so they're passed through as-is rather than doubling
+    // upstream load or overriding a legitimate NXDOMAIN. Returns the tag that actually produced the answer
+    // alongside it, since DNSSEC validation must chase the chain of trust through that same upstream.
+    async fn resolve_with_probe(&self, tag: &L, msg: &Message) -> Result<L, (L, Message)> {
+        let trial = self.upstreams.resolve(tag, msg).await?;
+        let is_address_query = msg
+            .queries()
+            .iter()
+            .next()
+            .map_or(false, |q| matches!(q.query_type(), RecordType::A | RecordType::AAAA));
+        match self.filter.get_probe(tag) {
+            Some(probe)
+                if is_address_query
+                    && trial.response_code() == ResponseCode::NoError
+                    && !IpCidrMatcher::new(probe.nets.clone()).contains_answer(&trial) =>
+            {
+                let resp = self.upstreams.resolve(&probe.trusted, msg).await?;
+                Ok((probe.trusted.clone(), resp))
+            }
+            _ => Ok((tag.clone(), trial)),
+        }
+    }
+
+    // Walk the DS/DNSKEY delegation from `self.trust_anchor` down to the zone owning `msg`'s answer (or,
+    // for a negative answer, its NSEC/NSEC3 denial proof), and cryptographically verify the relevant
+    // RRSIG(s) against it. Both branches chase the chain of trust; neither accepts an unsigned RRset unless
+    // its zone is proven insecure, which yields `Validity::ProvablyInsecure`.
+    async fn validate(&self, tag: &L, msg: &Message) -> Validity {
+        let result = if msg.answers().is_empty() {
+            dnssec::authenticate_denial(&self.upstreams, tag, msg, &self.trust_anchor).await
+        } else {
+            dnssec::authenticate_answer(&self.upstreams, tag, msg, &self.trust_anchor).await
+        };
+        match result {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("DNSSEC chain validation query failed: {}", e);
+                Validity::Bogus
             }
-        })
+        }
     }
 }
 
@@ -213,8 +267,11 @@ mod tests {
                 timeout: 10,
                 method: Udp("127.0.0.1:53533".parse().unwrap()),
                 tag: "mock".into(),
+                proxy: None,
+                proxy_auth: None,
             }],
             true,
+            false,
             0,
             "mock".into(),
             vec![],
@@ -0,0 +1,795 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Upstreams are where queries are finally dispatched to, tagged by `L` so `Filter` can route to them.
+
+use crate::error::{DrouteError, Result};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fmt::{Debug, Display},
+    hash::Hash,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::Mutex,
+    time::timeout as with_timeout,
+};
+use tokio_rustls::{
+    rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+use trust_dns_client::op::Message;
+
+/// The method/protocol an `Upstream` is reached by.
+pub enum UpstreamKind {
+    /// Plain, cleartext UDP.
+    Udp(SocketAddr),
+
+    /// DNS-over-TLS. The connection is a TCP stream wrapped in TLS, with each message prefixed by its
+    /// 2-byte big-endian length, same as classic TCP DNS. `domain` is used both for SNI and certificate
+    /// hostname validation.
+    Tls {
+        /// Address (usually port 853) of the DoT server.
+        addr: SocketAddr,
+        /// Domain the server's certificate is expected to be valid for.
+        domain: String,
+    },
+
+    /// DNS-over-HTTPS. The wire-format message is POSTed to `url` with `Content-Type: application/dns-message`,
+    /// and the response body is parsed the same way.
+    Https {
+        /// Full URL of the DoH endpoint, e.g. `https://1.1.1.1/dns-query`.
+        url: String,
+    },
+
+    /// Classic length-prefixed TCP DNS. Queried directly when configured, and also used transparently to
+    /// retry a `Udp` query whose response came back truncated.
+    Tcp(SocketAddr),
+}
+
+/// Username/password to use during the SOCKS5 sub-negotiation (RFC 1929).
+pub struct ProxyAuth {
+    /// Username.
+    pub username: String,
+    /// Password.
+    pub password: String,
+}
+
+/// A single upstream resolver: how to reach it, how long we're willing to wait, and the tag it answers to.
+pub struct Upstream<L> {
+    /// Timeout, in seconds, for a single query against this upstream.
+    pub timeout: u64,
+    /// How to reach the upstream.
+    pub method: UpstreamKind,
+    /// The tag rules refer to this upstream by.
+    pub tag: L,
+    /// Optional SOCKS5 proxy this upstream's traffic should be tunneled through.
+    pub proxy: Option<SocketAddr>,
+    /// Optional username/password for the proxy, used when `proxy` is set.
+    pub proxy_auth: Option<ProxyAuth>,
+}
+
+// Complete a SOCKS5 (RFC 1928/1929) handshake over `stream` and issue a CONNECT to `target`, returning once
+// the proxy's bind reply confirms the tunnel is up. Only IPv4/IPv6 targets are supported, which is all an
+// `UpstreamKind` ever needs.
+async fn socks5_connect(
+    proxy: SocketAddr,
+    target: SocketAddr,
+    auth: Option<&ProxyAuth>,
+) -> std::io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    // Greeting: advertise no-auth, and user/pass if we have credentials to offer.
+    let methods: &[u8] = if auth.is_some() { &[0x00, 0x02] } else { &[0x00] };
+    let mut greeting = vec![0x05, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await?;
+
+    let mut reply = [0_u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "not a SOCKS5 proxy",
+        ));
+    }
+    match reply[1] {
+        0x00 => {} // no auth required
+        0x02 => {
+            let auth = auth.ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "proxy requires username/password but none were configured",
+                )
+            })?;
+            let username_len: u8 = auth.username.len().try_into().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "SOCKS5 username must be at most 255 bytes",
+                )
+            })?;
+            let password_len: u8 = auth.password.len().try_into().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "SOCKS5 password must be at most 255 bytes",
+                )
+            })?;
+            let mut req = vec![0x01, username_len];
+            req.extend_from_slice(auth.username.as_bytes());
+            req.push(password_len);
+            req.extend_from_slice(auth.password.as_bytes());
+            stream.write_all(&req).await?;
+
+            let mut auth_reply = [0_u8; 2];
+            stream.read_exact(&mut auth_reply).await?;
+            if auth_reply[1] != 0x00 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "SOCKS5 authentication failed",
+                ));
+            }
+        }
+        0xff => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "SOCKS5 proxy rejected all offered authentication methods",
+            ))
+        }
+        m => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unsupported SOCKS5 auth method {}", m),
+            ))
+        }
+    }
+
+    // CONNECT request: VER CMD RSV ATYP DST.ADDR DST.PORT
+    let mut req = vec![0x05, 0x01, 0x00];
+    match target {
+        SocketAddr::V4(a) => {
+            req.push(0x01);
+            req.extend_from_slice(&a.ip().octets());
+        }
+        SocketAddr::V6(a) => {
+            req.push(0x04);
+            req.extend_from_slice(&a.ip().octets());
+        }
+    }
+    req.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&req).await?;
+
+    // Bind reply: VER REP RSV ATYP BND.ADDR BND.PORT. We only need to consume it to leave the stream clean.
+    let mut head = [0_u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != 0x00 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!("SOCKS5 CONNECT failed with reply code {}", head[1]),
+        ));
+    }
+    match head[3] {
+        0x01 => {
+            let mut rest = [0_u8; 4 + 2];
+            stream.read_exact(&mut rest).await?;
+        }
+        0x04 => {
+            let mut rest = [0_u8; 16 + 2];
+            stream.read_exact(&mut rest).await?;
+        }
+        0x03 => {
+            let mut len = [0_u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut rest = vec![0_u8; len[0] as usize + 2];
+            stream.read_exact(&mut rest).await?;
+        }
+        atyp => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unsupported SOCKS5 bind address type {}", atyp),
+            ))
+        }
+    }
+
+    Ok(stream)
+}
+
+// Open a plain or SOCKS5-tunneled TCP connection to `target`, depending on `proxy`.
+async fn dial(
+    target: SocketAddr,
+    proxy: Option<SocketAddr>,
+    auth: Option<&ProxyAuth>,
+) -> std::io::Result<TcpStream> {
+    match proxy {
+        Some(p) => socks5_connect(p, target, auth).await,
+        None => TcpStream::connect(target).await,
+    }
+}
+
+// How long an idle pooled TCP/TLS connection is kept around before it's reaped rather than reused.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How many idle connections are kept per upstream address. A burst of concurrent queries against the same
+// upstream all miss the pool, dial fresh, and then `put` back afterward; without a cap that burst would grow
+// the pool without bound until the next lazy reap, rather than actually being "a small pool of idle connections".
+const MAX_POOL_SIZE: usize = 8;
+
+// Reap anything in `conns` that's been sitting idle longer than `IDLE_TIMEOUT`, then hand back the freshest
+// survivor, if any. Shared by `TlsPool::get` and `TcpPool::get`.
+fn reap_and_pop<T>(conns: &mut Vec<(std::time::Instant, T)>) -> Option<T> {
+    conns.retain(|(since, _)| since.elapsed() < IDLE_TIMEOUT);
+    conns.pop().map(|(_, conn)| conn)
+}
+
+// A pool of warm TLS connections, keyed by the upstream address, so the handshake (which otherwise dominates
+// DoT latency) is only paid once per connection lifetime rather than once per query.
+struct TlsPool {
+    connector: TlsConnector,
+    idle: Mutex<HashMap<SocketAddr, Vec<(std::time::Instant, tokio_rustls::client::TlsStream<TcpStream>)>>>,
+}
+
+impl TlsPool {
+    fn new() -> Self {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns the connection alongside whether it came from the pool, so the caller only bothers retrying
+    // against a fresh connection when the one that just failed might genuinely have been stale — a
+    // connection that was already freshly dialed failing again means the upstream itself is unreachable,
+    // and retrying that is just paying the timeout twice for nothing.
+    async fn get(
+        &self,
+        addr: SocketAddr,
+        domain: &str,
+        proxy: Option<SocketAddr>,
+        proxy_auth: Option<&ProxyAuth>,
+    ) -> std::io::Result<(tokio_rustls::client::TlsStream<TcpStream>, bool)> {
+        let mut idle = self.idle.lock().await;
+        // DoT servers commonly close idle connections quickly; reap anything too old rather than hand back
+        // a connection that will fail mid-roundtrip.
+        if let Some(conn) = idle.get_mut(&addr).and_then(reap_and_pop) {
+            return Ok((conn, true));
+        }
+        drop(idle);
+        Ok((self.dial_fresh(addr, domain, proxy, proxy_auth).await?, false))
+    }
+
+    // Dial and handshake a brand new connection, bypassing the pool entirely. Used both when the pool is
+    // empty and to retry once a pooled connection that turned out to be stale.
+    async fn dial_fresh(
+        &self,
+        addr: SocketAddr,
+        domain: &str,
+        proxy: Option<SocketAddr>,
+        proxy_auth: Option<&ProxyAuth>,
+    ) -> std::io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let tcp = dial(addr, proxy, proxy_auth).await?;
+        let server_name: ServerName = domain
+            .try_into()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "bad domain"))?;
+        self.connector.connect(server_name, tcp).await
+    }
+
+    async fn put(&self, addr: SocketAddr, conn: tokio_rustls::client::TlsStream<TcpStream>) {
+        let mut idle = self.idle.lock().await;
+        let conns = idle.entry(addr).or_default();
+        if conns.len() < MAX_POOL_SIZE {
+            conns.push((std::time::Instant::now(), conn));
+        }
+    }
+}
+
+// A pool of idle plain-TCP connections, keyed by upstream address. Unlike `TlsPool` there's no handshake to
+// amortize, but a small idle pool still saves a SYN/ACK round trip on each query, which matters for upstreams
+// reached over a SOCKS5 proxy.
+struct TcpPool {
+    idle: Mutex<HashMap<SocketAddr, Vec<(std::time::Instant, TcpStream)>>>,
+}
+
+impl TcpPool {
+    fn new() -> Self {
+        Self {
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // See `TlsPool::get` for why the caller needs to know whether this came from the pool.
+    async fn get(
+        &self,
+        addr: SocketAddr,
+        proxy: Option<SocketAddr>,
+        proxy_auth: Option<&ProxyAuth>,
+    ) -> std::io::Result<(TcpStream, bool)> {
+        let mut idle = self.idle.lock().await;
+        if let Some(conn) = idle.get_mut(&addr).and_then(reap_and_pop) {
+            return Ok((conn, true));
+        }
+        drop(idle);
+        Ok((self.dial_fresh(addr, proxy, proxy_auth).await?, false))
+    }
+
+    // Dial a brand new connection, bypassing the pool entirely. Used both when the pool is empty and to
+    // retry once a pooled connection that turned out to be stale.
+    async fn dial_fresh(
+        &self,
+        addr: SocketAddr,
+        proxy: Option<SocketAddr>,
+        proxy_auth: Option<&ProxyAuth>,
+    ) -> std::io::Result<TcpStream> {
+        dial(addr, proxy, proxy_auth).await
+    }
+
+    async fn put(&self, addr: SocketAddr, conn: TcpStream) {
+        let mut idle = self.idle.lock().await;
+        let conns = idle.entry(addr).or_default();
+        if conns.len() < MAX_POOL_SIZE {
+            conns.push((std::time::Instant::now(), conn));
+        }
+    }
+}
+
+/// The full set of upstreams a `Router` knows about, indexed by tag.
+pub struct Upstreams<L> {
+    upstreams: HashMap<L, Upstream<L>>,
+    tls_pool: TlsPool,
+    tcp_pool: TcpPool,
+    http: reqwest::Client,
+}
+
+fn io_timeout<L: Display + Debug>() -> DrouteError<L> {
+    DrouteError::Io(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "upstream timed out",
+    ))
+}
+
+// Run `fut` with a per-query timeout, collapsing both the `Elapsed` and the inner I/O error into `DrouteError::Io`.
+async fn timed_out<L, T, E>(
+    secs: u64,
+    fut: impl std::future::Future<Output = std::result::Result<T, E>>,
+) -> Result<L, T>
+where
+    L: Display + Debug,
+    std::io::Error: From<E>,
+{
+    with_timeout(Duration::from_secs(secs), fut)
+        .await
+        .map_err(|_| io_timeout())?
+        .map_err(|e| DrouteError::Io(e.into()))
+}
+
+// Send a 2-byte length prefixed message and read the length-prefixed response back, as used by both TCP and TLS.
+async fn framed_roundtrip<L: Display + Debug, S: AsyncReadExt + AsyncWriteExt + Unpin>(
+    stream: &mut S,
+    msg: &Message,
+) -> Result<L, Message> {
+    let buf = msg.to_vec()?;
+    let len = (buf.len() as u16).to_be_bytes();
+    stream.write_all(&len).await?;
+    stream.write_all(&buf).await?;
+
+    let mut len_buf = [0_u8; 2];
+    stream.read_exact(&mut len_buf).await?;
+    let mut resp_buf = vec![0_u8; u16::from_be_bytes(len_buf) as usize];
+    stream.read_exact(&mut resp_buf).await?;
+    Ok(Message::from_vec(&resp_buf)?)
+}
+
+impl<L> Upstreams<L>
+where
+    L: 'static + Display + Debug + Eq + Hash + Send + Clone + Sync,
+{
+    /// Build a new set of upstreams. `cache_size` is accepted but not used: no answers are cached.
+    pub async fn new(upstreams: Vec<Upstream<L>>, _cache_size: usize) -> Result<L, Self> {
+        let mut map = HashMap::new();
+        for u in upstreams {
+            if matches!(u.method, UpstreamKind::Udp(_)) && u.proxy.is_some() {
+                // A SOCKS5 proxy only tunnels the TCP connections we open ourselves (resolve_tcp/resolve_tls),
+                // not a bare UDP socket; silently letting this through would mean the query reaches the real
+                // resolver unproxied, which is exactly what the `proxy` option promises not to do.
+                return Err(DrouteError::ProxiedUdpUnsupported(u.tag));
+            }
+            if map.contains_key(&u.tag) {
+                return Err(DrouteError::DuplicateTag(u.tag));
+            }
+            map.insert(u.tag.clone(), u);
+        }
+        Ok(Self {
+            upstreams: map,
+            tls_pool: TlsPool::new(),
+            tcp_pool: TcpPool::new(),
+            http: reqwest::Client::new(),
+        })
+    }
+
+    /// Whether at least one upstream is configured. Duplicate tags are already rejected by `new`, so by the
+    /// time a `Upstreams` exists its tags are guaranteed distinct; this only catches the empty-set case.
+    /// Named for parity with the exhaustiveness ("hybrid") check `Router` runs at startup.
+    pub fn hybrid_check(&self) -> Result<L, ()> {
+        if self.upstreams.is_empty() {
+            return Err(DrouteError::EmptyUpstreams);
+        }
+        Ok(())
+    }
+
+    /// Whether `tag` names a configured upstream.
+    pub fn exists(&self, tag: &L) -> Result<L, ()> {
+        if self.upstreams.contains_key(tag) {
+            Ok(())
+        } else {
+            Err(DrouteError::MissingTag(tag.clone()))
+        }
+    }
+
+    async fn resolve_udp(addr: SocketAddr, timeout: u64, msg: &Message) -> Result<L, Message> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+        socket.connect(addr).await?;
+        socket.send(&msg.to_vec()?).await?;
+        let mut buf = vec![0_u8; 4096];
+        let len = timed_out(timeout, socket.recv(&mut buf)).await?;
+        Ok(Message::from_vec(&buf[..len])?)
+    }
+
+    async fn resolve_tcp(
+        &self,
+        addr: SocketAddr,
+        timeout: u64,
+        proxy: Option<SocketAddr>,
+        proxy_auth: Option<&ProxyAuth>,
+        msg: &Message,
+    ) -> Result<L, Message> {
+        let (mut conn, pooled) = timed_out(timeout, self.tcp_pool.get(addr, proxy, proxy_auth)).await?;
+        let resp = match timed_out(timeout, framed_roundtrip(&mut conn, msg)).await {
+            Ok(resp) => resp,
+            // Only a pooled connection is worth retrying: the server may have closed it well before our own
+            // idle timeout elapsed. A freshly-dialed connection failing means the upstream itself is
+            // unreachable, and retrying that would just pay the timeout twice for nothing.
+            Err(_) if pooled => {
+                let mut conn = timed_out(timeout, self.tcp_pool.dial_fresh(addr, proxy, proxy_auth)).await?;
+                let resp = timed_out(timeout, framed_roundtrip(&mut conn, msg)).await?;
+                self.tcp_pool.put(addr, conn).await;
+                return Ok(resp);
+            }
+            Err(e) => return Err(e),
+        };
+        self.tcp_pool.put(addr, conn).await;
+        Ok(resp)
+    }
+
+    async fn resolve_tls(
+        &self,
+        addr: SocketAddr,
+        domain: &str,
+        timeout: u64,
+        proxy: Option<SocketAddr>,
+        proxy_auth: Option<&ProxyAuth>,
+        msg: &Message,
+    ) -> Result<L, Message> {
+        let (mut conn, pooled) = timed_out(
+            timeout,
+            self.tls_pool.get(addr, domain, proxy, proxy_auth),
+        )
+        .await?;
+        let resp = match timed_out(timeout, framed_roundtrip(&mut conn, msg)).await {
+            Ok(resp) => resp,
+            // Same rationale as resolve_tcp: only a pooled DoT connection is worth retrying, since a
+            // freshly-dialed one failing means the upstream is unreachable, not merely stale.
+            Err(_) if pooled => {
+                let mut conn = timed_out(
+                    timeout,
+                    self.tls_pool.dial_fresh(addr, domain, proxy, proxy_auth),
+                )
+                .await?;
+                let resp = timed_out(timeout, framed_roundtrip(&mut conn, msg)).await?;
+                self.tls_pool.put(addr, conn).await;
+                return Ok(resp);
+            }
+            Err(e) => return Err(e),
+        };
+        // The handshake is the expensive part, so hand a healthy connection back to the pool for reuse.
+        self.tls_pool.put(addr, conn).await;
+        Ok(resp)
+    }
+
+    async fn resolve_https(
+        &self,
+        url: &str,
+        timeout: u64,
+        proxy: Option<SocketAddr>,
+        proxy_auth: Option<&ProxyAuth>,
+        msg: &Message,
+    ) -> Result<L, Message> {
+        let client = match proxy {
+            Some(p) => {
+                let mut proxy_url = format!("socks5://{}", p);
+                if let Some(auth) = proxy_auth {
+                    proxy_url = format!("socks5://{}:{}@{}", auth.username, auth.password, p);
+                }
+                reqwest::Client::builder()
+                    .proxy(
+                        reqwest::Proxy::all(&proxy_url)
+                            .map_err(|e| DrouteError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?,
+                    )
+                    .build()
+                    .map_err(|e| DrouteError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?
+            }
+            None => self.http.clone(),
+        };
+        let send = client
+            .post(url)
+            .header("content-type", "application/dns-message")
+            .body(msg.to_vec()?)
+            .send();
+        let resp = with_timeout(Duration::from_secs(timeout), send)
+            .await
+            .map_err(|_| io_timeout())?
+            .map_err(|e| DrouteError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+        let body = resp
+            .bytes()
+            .await
+            .map_err(|e| DrouteError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+        Ok(Message::from_vec(&body)?)
+    }
+
+    /// Dispatch `msg` to the upstream tagged `tag`.
+    pub async fn resolve(&self, tag: &L, msg: &Message) -> Result<L, Message> {
+        let upstream = self
+            .upstreams
+            .get(tag)
+            .ok_or_else(|| DrouteError::MissingTag(tag.clone()))?;
+        let (proxy, proxy_auth) = (upstream.proxy, upstream.proxy_auth.as_ref());
+        match &upstream.method {
+            UpstreamKind::Udp(addr) => {
+                let resp = Self::resolve_udp(*addr, upstream.timeout, msg).await?;
+                if resp.truncated() {
+                    // The UDP answer didn't fit; retry the same query over TCP, same address, per RFC 1035.
+                    self.resolve_tcp(*addr, upstream.timeout, proxy, proxy_auth, msg)
+                        .await
+                } else {
+                    Ok(resp)
+                }
+            }
+            UpstreamKind::Tcp(addr) => {
+                self.resolve_tcp(*addr, upstream.timeout, proxy, proxy_auth, msg)
+                    .await
+            }
+            UpstreamKind::Tls { addr, domain } => {
+                self.resolve_tls(*addr, domain, upstream.timeout, proxy, proxy_auth, msg)
+                    .await
+            }
+            UpstreamKind::Https { url } => {
+                self.resolve_https(url, upstream.timeout, proxy, proxy_auth, msg)
+                    .await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use trust_dns_client::{
+        op::Query,
+        rr::{Name, RecordType},
+    };
+
+    #[tokio::test]
+    async fn test_resolve_tcp_retries_once_on_stale_pooled_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            // Each connection is answered then immediately dropped, simulating a server that closes
+            // connections well before our own idle timeout; the pooled connection handed back after the
+            // first query is already dead by the time the second query tries to reuse it.
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut len_buf = [0_u8; 2];
+                stream.read_exact(&mut len_buf).await.unwrap();
+                let mut buf = vec![0_u8; u16::from_be_bytes(len_buf) as usize];
+                stream.read_exact(&mut buf).await.unwrap();
+                let req = Message::from_vec(&buf).unwrap();
+                let mut resp = Message::new();
+                resp.set_id(req.id());
+                let out = resp.to_vec().unwrap();
+                stream.write_all(&(out.len() as u16).to_be_bytes()).await.unwrap();
+                stream.write_all(&out).await.unwrap();
+            }
+        });
+
+        let upstreams = Upstreams::new(
+            vec![Upstream {
+                timeout: 5,
+                method: UpstreamKind::Tcp(addr),
+                tag: "mock".to_string(),
+                proxy: None,
+                proxy_auth: None,
+            }],
+            0,
+        )
+        .await
+        .unwrap();
+
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            Name::from_utf8("example.com").unwrap(),
+            RecordType::A,
+        ));
+
+        upstreams.resolve(&"mock".to_string(), &query).await.unwrap();
+        // Give the server's connection-close a moment to actually reach the client before the pooled
+        // connection is reused.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        upstreams.resolve(&"mock".to_string(), &query).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_new_rejects_duplicate_tags() {
+        let upstreams = vec![
+            Upstream {
+                timeout: 5,
+                method: UpstreamKind::Udp("1.1.1.1:53".parse().unwrap()),
+                tag: "dup".to_string(),
+                proxy: None,
+                proxy_auth: None,
+            },
+            Upstream {
+                timeout: 5,
+                method: UpstreamKind::Udp("8.8.8.8:53".parse().unwrap()),
+                tag: "dup".to_string(),
+                proxy: None,
+                proxy_auth: None,
+            },
+        ];
+        let err = Upstreams::new(upstreams, 0).await.unwrap_err();
+        assert!(matches!(err, DrouteError::DuplicateTag(tag) if tag == "dup"));
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect_no_auth() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let target: SocketAddr = "93.184.216.34:80".parse().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0_u8; 2];
+            stream.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0_u8; greeting[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+            stream.write_all(&[0x05, 0x00]).await.unwrap(); // no auth required
+
+            let mut req_head = [0_u8; 4];
+            stream.read_exact(&mut req_head).await.unwrap();
+            assert_eq!(req_head[3], 0x01); // IPv4 address type
+            let mut addr_port = [0_u8; 4 + 2];
+            stream.read_exact(&mut addr_port).await.unwrap();
+
+            // Bind reply: success, IPv4 bound address.
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        socks5_connect(proxy_addr, target, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect_rejects_unsupported_auth_method() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let target: SocketAddr = "93.184.216.34:80".parse().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0_u8; 2];
+            stream.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0_u8; greeting[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+            stream.write_all(&[0x05, 0xff]).await.unwrap(); // no acceptable methods
+        });
+
+        let err = socks5_connect(proxy_addr, target, None).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect_username_password_auth() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let target: SocketAddr = "93.184.216.34:80".parse().unwrap();
+        let auth = ProxyAuth {
+            username: "user".into(),
+            password: "pass".into(),
+        };
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0_u8; 2];
+            stream.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0_u8; greeting[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+            assert!(methods.contains(&0x02));
+            stream.write_all(&[0x05, 0x02]).await.unwrap(); // require user/pass auth
+
+            let mut sub_head = [0_u8; 2];
+            stream.read_exact(&mut sub_head).await.unwrap();
+            let mut username = vec![0_u8; sub_head[1] as usize];
+            stream.read_exact(&mut username).await.unwrap();
+            assert_eq!(username, b"user");
+            let mut pass_len = [0_u8; 1];
+            stream.read_exact(&mut pass_len).await.unwrap();
+            let mut password = vec![0_u8; pass_len[0] as usize];
+            stream.read_exact(&mut password).await.unwrap();
+            assert_eq!(password, b"pass");
+            stream.write_all(&[0x01, 0x00]).await.unwrap(); // auth succeeded
+
+            let mut req_head = [0_u8; 4];
+            stream.read_exact(&mut req_head).await.unwrap();
+            let mut addr_port = [0_u8; 4 + 2];
+            stream.read_exact(&mut addr_port).await.unwrap();
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        socks5_connect(proxy_addr, target, Some(&auth)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect_rejects_oversized_credentials() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let target: SocketAddr = "93.184.216.34:80".parse().unwrap();
+        let auth = ProxyAuth {
+            username: "a".repeat(256),
+            password: "pass".into(),
+        };
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0_u8; 2];
+            stream.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0_u8; greeting[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+            stream.write_all(&[0x05, 0x02]).await.unwrap(); // require user/pass auth
+        });
+
+        let err = socks5_connect(proxy_addr, target, Some(&auth)).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}